@@ -0,0 +1,29 @@
+use std::{path::Path, process::Command, time::SystemTime};
+
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    Command::new(program)
+        .args(args)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|output| output.trim().to_string())
+}
+
+fn main() {
+    let build_date = std::env::var("BUILD_DATE")
+        .unwrap_or_else(|_| humantime::format_rfc3339_seconds(SystemTime::now()).to_string());
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let rustc_version = command_output(&rustc, &["--version"]).unwrap_or_default();
+    let git_sha = command_output("git", &["rev-parse", "HEAD"]).unwrap_or_default();
+
+    println!("cargo:rustc-env=IMSERIOUS_BUILD_DATE={}", build_date);
+    println!("cargo:rustc-env=IMSERIOUS_RUSTC_VERSION={}", rustc_version);
+    println!("cargo:rustc-env=IMSERIOUS_GIT_SHA={}", git_sha);
+
+    println!("cargo:rerun-if-env-changed=BUILD_DATE");
+    if Path::new(".git/HEAD").exists() {
+        println!("cargo:rerun-if-changed=.git/HEAD");
+        println!("cargo:rerun-if-changed=.git/refs");
+    }
+}
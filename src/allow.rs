@@ -0,0 +1,120 @@
+use tokio::time::Duration;
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, RwLock},
+};
+
+use crate::config::{AllowEntry, AllowRule};
+
+// The allow rules, with the current addresses of any hostnames among them.
+// An absent list allows everything, and an empty one denies everything
+pub struct AllowList {
+    rules: Option<Vec<AllowRule>>,
+    resolved: RwLock<HashMap<String, Vec<IpAddr>>>,
+}
+
+impl AllowList {
+    pub async fn new(rules: Option<Vec<AllowRule>>) -> Self {
+        let list = Self {
+            rules,
+            resolved: RwLock::new(HashMap::new()),
+        };
+        list.resolve().await;
+        list
+    }
+
+    pub fn rules(&self) -> Option<&[AllowRule]> {
+        self.rules.as_deref()
+    }
+
+    pub fn has_hostnames(&self) -> bool {
+        self.hostnames().next().is_some()
+    }
+
+    // The first matching rule decides, denying if none match
+    pub fn permits(&self, ip: &IpAddr) -> bool {
+        let rules = match &self.rules {
+            Some(rules) => rules,
+            None => return true,
+        };
+        let resolved = self.resolved.read().unwrap_or_else(|e| e.into_inner());
+        rules
+            .iter()
+            .find(|rule| match &rule.entry {
+                AllowEntry::Net(net) => net.contains(ip),
+                AllowEntry::Host(host) => {
+                    resolved.get(host).is_some_and(|addrs| addrs.contains(ip))
+                }
+            })
+            .is_some_and(|rule| !rule.deny)
+    }
+
+    fn hostnames(&self) -> impl Iterator<Item = &str> {
+        self.rules
+            .iter()
+            .flatten()
+            .filter_map(|rule| match &rule.entry {
+                AllowEntry::Host(host) => Some(host.as_str()),
+                AllowEntry::Net(_) => None,
+            })
+    }
+
+    // Failed lookups keep any previously resolved addresses
+    async fn resolve(&self) {
+        for host in self.hostnames() {
+            match tokio::net::lookup_host((host, 0)).await {
+                Ok(addrs) => {
+                    let mut addrs: Vec<_> = addrs.map(|addr| addr.ip()).collect();
+                    addrs.sort();
+                    addrs.dedup();
+                    tracing::info!(%host, ?addrs, "allow_resolve");
+                    self.resolved
+                        .write()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .insert(host.to_string(), addrs);
+                }
+                Err(error) => tracing::warn!(%host, %error, "allow_resolve"),
+            }
+        }
+    }
+
+    // Re-resolve hostnames on SIGHUP, and every ttl if given
+    pub async fn refresh(self: Arc<Self>, ttl: Option<Duration>) {
+        #[cfg(unix)]
+        let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+            .map_err(|error| tracing::warn!(%error, "allow_resolve"))
+            .ok();
+
+        let mut interval = ttl.map(|ttl| {
+            let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + ttl, ttl);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            interval
+        });
+
+        loop {
+            let hup = async {
+                #[cfg(unix)]
+                if let Some(hangup) = &mut hangup {
+                    return hangup.recv().await;
+                }
+                std::future::pending::<Option<()>>().await
+            };
+            let tick = async {
+                match &mut interval {
+                    Some(interval) => interval.tick().await,
+                    None => std::future::pending().await,
+                }
+            };
+
+            tokio::select! {
+                signal = hup => if signal.is_none() {
+                    return;
+                },
+                _ = tick => (),
+            }
+            self.resolve().await;
+        }
+    }
+}
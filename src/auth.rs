@@ -0,0 +1,159 @@
+use anyhow::{Context, Result};
+use axum::{
+    http::{header::AUTHORIZATION, Request, StatusCode},
+    response::{IntoResponse, Response},
+};
+use base64::Engine;
+use htpasswd_verify::Htpasswd;
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use crate::config::Auth;
+
+#[derive(Clone)]
+pub enum BasicAuth {
+    Single {
+        user: String,
+        pass: String,
+    },
+    // Each user's line, parsed again only to verify a password against it
+    Htpasswd {
+        path: PathBuf,
+        entries: Arc<RwLock<HashMap<String, String>>>,
+    },
+}
+
+impl BasicAuth {
+    pub async fn from_config(auth: &Auth) -> Result<Self> {
+        match (&auth.htpasswd_file, &auth.user, &auth.pass) {
+            (Some(path), _, _) => Ok(Self::Htpasswd {
+                path: path.clone(),
+                entries: Arc::new(RwLock::new(read_htpasswd(path).await?)),
+            }),
+            (None, Some(user), Some(pass)) => Ok(Self::Single {
+                user: user.clone(),
+                pass: pass.clone(),
+            }),
+            _ => anyhow::bail!("auth requires user and pass, or htpasswd_file"),
+        }
+    }
+
+    pub async fn validate<B>(&self, request: Request<B>) -> Result<Request<B>, Response> {
+        let credentials = request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|header| header.to_str().ok())
+            .and_then(|header| header.strip_prefix("Basic "))
+            .and_then(|encoded| {
+                base64::engine::general_purpose::STANDARD
+                    .decode(encoded.trim())
+                    .ok()
+            })
+            .and_then(|decoded| String::from_utf8(decoded).ok());
+
+        let authorized = match &credentials {
+            Some(credentials) => {
+                let (user, pass) = credentials
+                    .split_once(':')
+                    .unwrap_or((credentials.as_str(), ""));
+                self.check(user, pass).await
+            }
+            None => false,
+        };
+
+        if authorized {
+            Ok(request)
+        } else {
+            Err(StatusCode::UNAUTHORIZED.into_response())
+        }
+    }
+
+    async fn check(&self, user: &str, pass: &str) -> bool {
+        match self {
+            Self::Single {
+                user: expected_user,
+                pass: expected_pass,
+            } => {
+                // Avoid short-circuiting so both are always compared
+                constant_time_eq(user.as_bytes(), expected_user.as_bytes())
+                    & constant_time_eq(pass.as_bytes(), expected_pass.as_bytes())
+            }
+            Self::Htpasswd { entries, .. } => {
+                let entry = entries
+                    .read()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .get(user)
+                    .cloned();
+                let entry = match entry {
+                    Some(entry) => entry,
+                    None => return false,
+                };
+
+                // Hashes like bcrypt are deliberately slow, keep them off the runtime
+                let (user, pass) = (user.to_string(), pass.to_string());
+                tokio::task::spawn_blocking(move || {
+                    Htpasswd::from(entry.as_str()).check(&user, &pass)
+                })
+                .await
+                .unwrap_or(false)
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    pub async fn reload_on_hangup(self) {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let (path, entries) = match self {
+            Self::Htpasswd { path, entries } => (path, entries),
+            Self::Single { .. } => return,
+        };
+
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(error) => {
+                tracing::warn!(%error, "htpasswd_reload");
+                return;
+            }
+        };
+
+        while hangup.recv().await.is_some() {
+            match read_htpasswd(&path).await {
+                Ok(new) => {
+                    *entries.write().unwrap_or_else(|e| e.into_inner()) = new;
+                    tracing::info!(path=%path.display(), reload=%"success", "htpasswd");
+                }
+                Err(error) => {
+                    tracing::error!(path=%path.display(), reload=%"error", %error, "htpasswd")
+                }
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub async fn reload_on_hangup(self) {}
+}
+
+async fn read_htpasswd(path: &Path) -> Result<HashMap<String, String>> {
+    let contents = tokio::fs::read_to_string(path)
+        .await
+        .with_context(|| format!("reading {}", path.display()))?;
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            line.split_once(':')
+                .map(|(user, _)| (user.to_string(), line.to_string()))
+        })
+        .collect())
+}
+
+// Leaks only the length of the inputs
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
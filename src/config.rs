@@ -1,35 +1,183 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
+use governor::Quota;
 use serde::Deserialize;
-use strum::{Display, EnumString};
+use strum::{Display, EnumString, EnumVariantNames, VariantNames};
 use tokio::process::Command;
 
 use std::{
-    num::{NonZeroU16, NonZeroU32},
+    collections::{HashMap, HashSet},
+    ffi::OsString,
+    num::{NonZeroU16, NonZeroU32, NonZeroUsize},
+    path::{Path, PathBuf},
     str::FromStr,
+    sync::{Arc, Mutex},
     time::Duration,
 };
 
-use crate::message::ImseEvent;
+use crate::{
+    limiter::KeyedLimiter,
+    message::{ImseEvent, ImseMessage},
+    state::StateStore,
+    userlist::UserList,
+};
+
+// Bumped when a release needs existing configurations rewritten, appending a
+// migration from the previous version to MIGRATIONS.  Files declaring an
+// older version, or none at all, are migrated in order before
+// deserialization, and newer versions are rejected.
+//
+// Changelog:
+//   1: The original format, assumed when version is absent.
+//   2: An empty allow list denies all notifications rather than allowing
+//      them, introduced alongside distinguishing an absent allow from an
+//      empty one.  migrate_v1_to_v2 removes `allow = []`, keeping older
+//      files allowing everything.
+const CONFIG_VERSION: u32 = 2;
+const MIGRATIONS: [fn(&mut toml::Table); CONFIG_VERSION as usize - 1] = [migrate_v1_to_v2];
+
+// An empty allow list allowed everything before version 2, and denies
+// everything since
+fn migrate_v1_to_v2(config: &mut toml::Table) {
+    if matches!(config.get("allow"), Some(toml::Value::Array(allow)) if allow.is_empty()) {
+        config.remove("allow");
+    }
+}
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct Config {
     #[serde(default)]
     pub listen: Option<std::net::SocketAddr>,
     #[serde(default)]
-    pub allow: Vec<ipnet::IpNet>,
+    pub bind_device: Option<String>,
+    #[serde(default)]
+    pub allow: Option<Vec<AllowRule>>,
+    #[serde(default)]
+    pub allow_dns_ttl: Option<NonZeroDuration>,
     #[serde(default)]
     pub endpoint: Option<String>,
     #[serde(default)]
     pub max_connections: Option<NonZeroU16>,
     #[serde(default)]
+    pub load_shed_queue: Option<NonZeroUsize>,
+    #[serde(default)]
+    pub max_handlers_per_message: Option<NonZeroUsize>,
+    #[serde(default)]
+    pub max_requests_per_ip_per_second: Option<NonZeroU32>,
+    #[serde(default)]
     pub timeout: Option<NonZeroDuration>,
     #[serde(default)]
+    pub read_timeout: Option<NonZeroDuration>,
+    #[serde(default)]
+    pub write_timeout: Option<NonZeroDuration>,
+    #[serde(default)]
+    pub sync_response_timeout: Option<NonZeroDuration>,
+    #[serde(default)]
+    pub connection_timeout: Option<NonZeroDuration>,
+    #[serde(default)]
+    pub max_body_bytes: Option<usize>,
+    #[serde(default)]
+    pub max_header_bytes: Option<usize>,
+    #[serde(default)]
+    pub sync_response: bool,
+    #[serde(default)]
+    pub user_normalize: bool,
+    #[serde(default)]
+    pub strict_content_type: Option<bool>,
+    #[serde(default)]
+    pub idempotency_window: Option<NonZeroDuration>,
+    #[serde(default)]
+    pub max_idempotency_keys: Option<NonZeroUsize>,
+    #[serde(default)]
+    pub http_version: HttpVersion,
+    #[serde(default)]
     pub auth: Option<Auth>,
     #[serde(default)]
     pub tls: Option<TlsConfig>,
     #[serde(default)]
     pub log: Logging,
+    #[serde(default)]
+    pub state_dir: Option<PathBuf>,
+    #[serde(default)]
+    pub rate_limit_group: Vec<RateLimitGroup>,
+    #[serde(deserialize_with = "deserialize_handlers")]
     pub handler: Vec<Handler>,
+    // Errors setting up handlers after parsing, reported by validate
+    #[serde(skip)]
+    pub load_errors: Vec<String>,
+}
+
+// Deserialization stops at the first error, so handlers are retried one by
+// one to report every one that is invalid
+fn handler_errors(raw: &toml::Table) -> Vec<String> {
+    let handlers: Vec<(String, &toml::Value)> = match raw.get("handler") {
+        Some(toml::Value::Array(handlers)) => handlers
+            .iter()
+            .enumerate()
+            .map(|(index, handler)| (format!("handler[{}]", index), handler))
+            .collect(),
+        // Tables are sorted by name, so their index isn't known here
+        Some(toml::Value::Table(handlers)) => handlers
+            .iter()
+            .map(|(name, handler)| (format!("handler ({})", name), handler))
+            .collect(),
+        _ => vec![],
+    };
+
+    handlers
+        .into_iter()
+        .filter_map(|(label, handler)| {
+            Handler::deserialize(handler.clone())
+                .err()
+                .map(|error| format!("{}: {}", label, error))
+        })
+        .collect()
+}
+
+// Handlers may be an array of tables, or tables keyed by handler name
+fn deserialize_handlers<'de, D>(deserializer: D) -> Result<Vec<Handler>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    struct HandlersVisitor;
+
+    impl<'de> serde::de::Visitor<'de> for HandlersVisitor {
+        type Value = Vec<Handler>;
+
+        fn expecting(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.write_str("an array of handlers, or handlers keyed by name")
+        }
+
+        fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+        where
+            A: serde::de::SeqAccess<'de>,
+        {
+            let mut handlers = vec![];
+            while let Some(handler) = seq.next_element()? {
+                handlers.push(handler);
+            }
+            Ok(handlers)
+        }
+
+        fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+        where
+            A: serde::de::MapAccess<'de>,
+        {
+            let mut handlers = vec![];
+            while let Some((name, mut handler)) = map.next_entry::<String, Handler>()? {
+                if matches!(&handler.name, Some(inner) if *inner != name) {
+                    return Err(serde::de::Error::custom(format!(
+                        "handler {} has a conflicting name",
+                        name
+                    )));
+                }
+                handler.name = Some(name);
+                handlers.push(handler);
+            }
+            Ok(handlers)
+        }
+    }
+
+    deserializer.deserialize_any(HandlersVisitor)
 }
 
 #[derive(Clone, Debug, Default, Deserialize)]
@@ -41,14 +189,129 @@ pub struct Logging {
     #[serde(default)]
     pub timestamp: bool,
     #[serde(default)]
+    pub timestamp_format: Option<String>,
+    #[serde(default)]
     pub target: bool,
     #[serde(default)]
     pub ansi: bool,
     #[serde(default)]
     pub format: LoggingFormat,
+    #[serde(default)]
+    pub file: Option<PathBuf>,
+    #[serde(default)]
+    pub file_format: Option<LoggingFormat>,
+    #[serde(default)]
+    pub filter_events: Option<Vec<ImseEvent>>,
+    #[serde(default)]
+    pub event_fields: Option<Vec<EventField>>,
+    #[serde(default)]
+    pub suppress_health_checks: bool,
+    #[serde(default)]
+    pub health_check_paths: Option<Vec<String>>,
+    #[serde(default)]
+    pub request_log: Option<bool>,
+    #[serde(default)]
+    pub request_log_level: Option<LoggingLevel>,
+    #[serde(default)]
+    pub target_filter: Option<HashMap<String, LoggingLevel>>,
+    #[serde(default)]
+    pub sanitize_fields: Option<Vec<String>>,
+    #[serde(default)]
+    pub sanitize_defaults: bool,
 }
 
-#[derive(Copy, Clone, Debug, Display, Deserialize, Hash, PartialEq, Eq, EnumString)]
+// PATH for commands with clear_env, unless path is given
+const CLEAR_ENV_PATH: &str = "/usr/local/bin:/usr/bin:/bin";
+
+// Fields which may carry message content or addresses
+const SANITIZE_DEFAULTS: [&str; 7] = [
+    "from",
+    "snippet",
+    "subject",
+    "IMSE_FROM",
+    "IMSE_SNIPPET",
+    "IMSE_MESSAGE_EXTRA_JSON",
+    "IMSE_BATCH_JSON",
+];
+
+// Common probe paths used by load balancers and orchestrators
+const HEALTH_CHECK_PATHS: [&str; 4] = ["/health", "/healthz", "/livez", "/readyz"];
+
+impl Logging {
+    // Request paths suppress_health_checks applies to
+    pub fn health_check_paths(&self) -> Vec<String> {
+        self.health_check_paths.clone().unwrap_or_else(|| {
+            HEALTH_CHECK_PATHS
+                .iter()
+                .map(|path| path.to_string())
+                .collect()
+        })
+    }
+
+    // EnvFilter directives for target_filter, like tower_http=warn
+    pub fn target_directives(&self) -> Vec<String> {
+        self.target_filter
+            .iter()
+            .flatten()
+            .map(|(target, level)| format!("{}={}", target, level.inner()))
+            .collect()
+    }
+
+    // Log fields and command environment variables to mask
+    pub fn sanitized_fields(&self) -> HashSet<String> {
+        let defaults = self.sanitize_defaults.then_some(SANITIZE_DEFAULTS);
+        self.sanitize_fields
+            .iter()
+            .flatten()
+            .map(String::as_str)
+            .chain(defaults.into_iter().flatten())
+            .map(str::to_string)
+            .collect()
+    }
+}
+
+#[derive(
+    Copy, Clone, Debug, Display, Deserialize, Hash, PartialEq, Eq, EnumString, EnumVariantNames,
+)]
+#[strum(serialize_all = "snake_case")]
+#[serde(try_from = "String")]
+pub enum EventField {
+    Event,
+    User,
+    Folder,
+    From,
+    Unseen,
+    RemoteAddr,
+}
+
+impl TryFrom<String> for EventField {
+    type Error = String;
+
+    fn try_from(string: String) -> Result<Self, Self::Error> {
+        Self::from_str(&string).map_err(|_| {
+            format!(
+                "Unknown event field '{}'. Valid values: {}",
+                string,
+                Self::VARIANTS.join(", ")
+            )
+        })
+    }
+}
+
+impl EventField {
+    // Everything but the potentially sensitive from address
+    pub const DEFAULT: [Self; 5] = [
+        Self::Event,
+        Self::User,
+        Self::Folder,
+        Self::Unseen,
+        Self::RemoteAddr,
+    ];
+}
+
+#[derive(
+    Copy, Clone, Debug, Display, Deserialize, Hash, PartialEq, Eq, EnumString, EnumVariantNames,
+)]
 #[strum(ascii_case_insensitive)]
 #[serde(try_from = "String")]
 pub enum LoggingFormat {
@@ -59,10 +322,16 @@ pub enum LoggingFormat {
 }
 
 impl TryFrom<String> for LoggingFormat {
-    type Error = strum::ParseError;
+    type Error = String;
 
     fn try_from(string: String) -> Result<Self, Self::Error> {
-        Self::from_str(&string)
+        Self::from_str(&string).map_err(|_| {
+            format!(
+                "Unknown log format '{}'. Valid values: {}",
+                string,
+                Self::VARIANTS.join(", ")
+            )
+        })
     }
 }
 
@@ -77,10 +346,15 @@ impl Default for LoggingFormat {
 pub struct LoggingLevel(tracing::Level);
 
 impl TryFrom<String> for LoggingLevel {
-    type Error = tracing::metadata::ParseLevelError;
+    type Error = String;
 
     fn try_from(string: String) -> Result<Self, Self::Error> {
-        Ok(Self(tracing::Level::from_str(&string)?))
+        tracing::Level::from_str(&string).map(Self).map_err(|_| {
+            format!(
+                "Unknown log level '{}'. Valid values: error, warn, info, debug, trace",
+                string
+            )
+        })
     }
 }
 
@@ -98,34 +372,362 @@ impl LoggingLevel {
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct Auth {
-    pub user: String,
-    pub pass: String,
+    #[serde(default)]
+    pub user: Option<String>,
+    #[serde(default)]
+    pub pass: Option<String>,
+    #[serde(default)]
+    pub htpasswd_file: Option<PathBuf>,
+    #[serde(default)]
+    pub realm: Option<String>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct TlsConfig {
-    pub cert: String,
-    pub key: String,
+    #[serde(default)]
+    pub cert: Option<String>,
+    #[serde(default)]
+    pub cert_pem: Option<String>,
+    #[serde(default)]
+    pub key: Option<String>,
+    #[serde(default)]
+    pub key_pem: Option<String>,
+    #[serde(default)]
+    pub key_password: Option<String>,
     #[serde(default)]
     pub periodic_reload: Option<NonZeroDuration>,
+    #[serde(default)]
+    pub session_ticket_rotation: Option<NonZeroDuration>,
+    #[serde(default)]
+    pub alpn: Option<Vec<String>>,
+    #[serde(default)]
+    pub http2_only: bool,
 }
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct Handler {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub disable: bool,
+    #[serde(default)]
+    pub tags: Option<Vec<String>>,
+    #[serde(default)]
+    pub log_level: Option<LoggingLevel>,
+    #[serde(default)]
+    pub exclusive: bool,
+    #[serde(default)]
+    pub dry_run: bool,
     #[serde(default)]
     pub ip: Vec<ipnet::IpNet>,
-    pub user: String,
     #[serde(default)]
-    pub event: ImseEvent,
+    pub user: Option<String>,
+    #[serde(default)]
+    pub user_pattern: Option<RegexPattern>,
+    #[serde(default)]
+    pub user_list_file: Option<PathBuf>,
+    #[serde(default)]
+    pub event: Option<ImseEvent>,
+    #[serde(default)]
+    pub event_pattern: Option<GlobPattern>,
+    #[serde(default)]
+    pub event_coalesce: bool,
+    #[serde(default)]
+    pub folder: Option<String>,
+    #[serde(default)]
+    pub folder_match_mode: Option<FolderMatchMode>,
+    #[serde(default)]
+    pub snippet_required: bool,
+    #[serde(default)]
+    pub from_required: bool,
     #[serde(default)]
     pub delay: Option<NonZeroDuration>,
     #[serde(default)]
+    pub debounce_reset: bool,
+    #[serde(default)]
+    pub dedup_by_uid: bool,
+    #[serde(default)]
+    pub dedup_ttl: Option<NonZeroDuration>,
+    #[serde(default)]
+    pub fan_in_window: Option<NonZeroDuration>,
+    #[serde(default)]
+    pub fan_in_min_events: Option<u32>,
+    #[serde(default)]
     pub limit_period: Option<NonZeroDuration>,
     #[serde(default)]
-    pub limit_burst: Option<NonZeroU32>,
+    pub limit_burst: Option<u32>,
+    #[serde(default)]
+    pub rate_limit_mode: Option<RateLimitMode>,
+    #[serde(default)]
+    pub rate_limit_per_folder: bool,
+    #[serde(default)]
+    pub rate_limit_group: Option<String>,
     #[serde(default)]
     pub periodic: Option<NonZeroDuration>,
-    pub command: SplitCommand,
+    #[serde(default)]
+    pub periodic_align: bool,
+    #[serde(default)]
+    pub periodic_immediate: bool,
+    #[serde(default)]
+    pub periodic_jitter: Option<NonZeroDuration>,
+    #[serde(default)]
+    pub periodic_always_fresh: bool,
+    #[serde(default)]
+    pub catchup: bool,
+    #[serde(default)]
+    pub max_catchup: Option<u32>,
+    #[serde(default)]
+    pub success_codes: Option<Vec<i32>>,
+    #[serde(default)]
+    pub command: Option<SplitCommand>,
+    #[serde(default)]
+    pub script: Option<String>,
+    #[serde(default)]
+    pub command_args_template: Option<Vec<String>>,
+    #[serde(default)]
+    pub command_stdin_template: Option<String>,
+    #[serde(default)]
+    pub stdin_file: Option<PathBuf>,
+    #[serde(default)]
+    pub command_env_json: bool,
+    #[serde(default)]
+    pub wasm_filter: Option<PathBuf>,
+    #[serde(default)]
+    pub pre_command: Option<SplitCommand>,
+    #[serde(default)]
+    pub post_command: Option<SplitCommand>,
+    #[serde(default)]
+    pub notify_on_rate_limit: Option<SplitCommand>,
+    #[serde(default)]
+    pub on_error_command: Option<SplitCommand>,
+    #[serde(default)]
+    pub fail_fast: bool,
+    #[serde(default)]
+    pub skip_if_running: bool,
+    #[serde(default)]
+    pub command_timeout: Option<NonZeroDuration>,
+    #[serde(default)]
+    pub kill_grace_period: Option<NonZeroDuration>,
+    #[serde(default)]
+    pub path: Option<String>,
+    #[serde(default)]
+    pub clear_env: bool,
+    #[serde(default)]
+    pub capture_output: bool,
+    #[serde(default)]
+    pub output_on_failure_only: bool,
+    #[serde(skip)]
+    pub folder_matcher: Option<FolderMatcher>,
+    #[serde(skip)]
+    pub index: usize,
+    #[serde(skip)]
+    pub state: Option<StateStore>,
+    #[serde(skip)]
+    pub sanitize_env: HashSet<String>,
+    #[serde(skip)]
+    pub user_list: Option<Arc<UserList>>,
+    #[serde(skip)]
+    pub shared_limiter: Option<Arc<Mutex<KeyedLimiter>>>,
+}
+
+// A rate limit shared by every handler naming it in rate_limit_group
+#[derive(Clone, Debug, Deserialize)]
+pub struct RateLimitGroup {
+    pub name: String,
+    #[serde(default)]
+    pub limit_period: Option<NonZeroDuration>,
+    #[serde(default)]
+    pub limit_burst: Option<NonZeroU32>,
+    #[serde(default)]
+    pub rate_limit_mode: Option<RateLimitMode>,
+}
+
+impl RateLimitGroup {
+    pub fn limit_period(&self) -> Duration {
+        self.limit_period
+            .map_or(Duration::from_secs(30), Duration::from)
+    }
+
+    pub fn limit_burst(&self) -> NonZeroU32 {
+        self.limit_burst.unwrap_or(NonZeroU32::MIN)
+    }
+}
+
+#[derive(
+    Copy, Clone, Debug, Display, Deserialize, Hash, PartialEq, Eq, EnumString, EnumVariantNames,
+)]
+#[strum(ascii_case_insensitive)]
+#[serde(try_from = "String")]
+pub enum RateLimitMode {
+    TokenBucket,
+    FixedWindow,
+    SlidingWindow,
+}
+
+impl TryFrom<String> for RateLimitMode {
+    type Error = String;
+
+    fn try_from(string: String) -> Result<Self, Self::Error> {
+        Self::from_str(&string).map_err(|_| {
+            format!(
+                "Unknown rate limit mode '{}'. Valid values: {}",
+                string,
+                Self::VARIANTS.join(", ")
+            )
+        })
+    }
+}
+
+impl Default for RateLimitMode {
+    fn default() -> Self {
+        Self::TokenBucket
+    }
+}
+
+#[derive(
+    Copy, Clone, Debug, Display, Deserialize, Hash, PartialEq, Eq, EnumString, EnumVariantNames,
+)]
+#[strum(serialize_all = "lowercase")]
+#[serde(try_from = "String")]
+pub enum HttpVersion {
+    Http1,
+    Http2,
+    Auto,
+}
+
+impl TryFrom<String> for HttpVersion {
+    type Error = String;
+
+    fn try_from(string: String) -> Result<Self, Self::Error> {
+        Self::from_str(&string).map_err(|_| {
+            format!(
+                "Unknown HTTP version '{}'. Valid values: {}",
+                string,
+                Self::VARIANTS.join(", ")
+            )
+        })
+    }
+}
+
+impl Default for HttpVersion {
+    fn default() -> Self {
+        Self::Auto
+    }
+}
+
+#[derive(
+    Copy, Clone, Debug, Display, Deserialize, Hash, PartialEq, Eq, EnumString, EnumVariantNames,
+)]
+#[strum(ascii_case_insensitive)]
+#[serde(try_from = "String")]
+pub enum FolderMatchMode {
+    Exact,
+    Prefix,
+    Glob,
+    Regex,
+}
+
+impl TryFrom<String> for FolderMatchMode {
+    type Error = String;
+
+    fn try_from(string: String) -> Result<Self, Self::Error> {
+        Self::from_str(&string).map_err(|_| {
+            format!(
+                "Unknown folder match mode '{}'. Valid values: {}",
+                string,
+                Self::VARIANTS.join(", ")
+            )
+        })
+    }
+}
+
+impl Default for FolderMatchMode {
+    fn default() -> Self {
+        Self::Glob
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum FolderMatcher {
+    Exact(String),
+    Prefix(String),
+    Glob(glob::Pattern),
+    Regex(regex::Regex),
+}
+
+impl FolderMatcher {
+    pub fn new(mode: FolderMatchMode, pattern: &str) -> Result<Self> {
+        Ok(match mode {
+            FolderMatchMode::Exact => Self::Exact(pattern.to_string()),
+            FolderMatchMode::Prefix => Self::Prefix(pattern.to_string()),
+            FolderMatchMode::Glob => Self::Glob(glob::Pattern::new(pattern)?),
+            FolderMatchMode::Regex => Self::Regex(regex::Regex::new(pattern)?),
+        })
+    }
+
+    pub fn is_match(&self, folder: &str) -> bool {
+        match self {
+            Self::Exact(expected) => expected == folder,
+            Self::Prefix(prefix) => folder.starts_with(prefix.as_str()),
+            Self::Glob(pattern) => pattern.matches(folder),
+            Self::Regex(regex) => regex.is_match(folder),
+        }
+    }
+}
+
+// A CIDR range, IP address, or hostname resolved at runtime, denied rather
+// than allowed with a leading !
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "String")]
+pub struct AllowRule {
+    pub deny: bool,
+    pub entry: AllowEntry,
+}
+
+#[derive(Clone, Debug)]
+pub enum AllowEntry {
+    Net(ipnet::IpNet),
+    Host(String),
+}
+
+impl TryFrom<String> for AllowRule {
+    type Error = String;
+
+    fn try_from(string: String) -> Result<Self, Self::Error> {
+        let (deny, entry) = match string.strip_prefix('!') {
+            Some(entry) => (true, entry),
+            None => (false, string.as_str()),
+        };
+        let entry = if let Ok(net) = entry.parse() {
+            AllowEntry::Net(net)
+        } else if let Ok(ip) = entry.parse::<std::net::IpAddr>() {
+            AllowEntry::Net(ip.into())
+        } else if !entry.is_empty()
+            && entry
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+        {
+            AllowEntry::Host(entry.to_ascii_lowercase())
+        } else {
+            return Err(format!(
+                "Invalid allow entry '{}': expected a CIDR range, IP address or hostname",
+                string
+            ));
+        };
+        Ok(Self { deny, entry })
+    }
+}
+
+impl std::fmt::Display for AllowRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.deny {
+            write!(f, "!")?;
+        }
+        match &self.entry {
+            AllowEntry::Net(net) => write!(f, "{}", net),
+            AllowEntry::Host(host) => write!(f, "{}", host),
+        }
+    }
 }
 
 #[derive(Clone, Copy, Debug, Deserialize)]
@@ -159,7 +761,55 @@ impl NonZeroDuration {
 
 #[derive(Clone, Debug, Deserialize)]
 #[serde(try_from = "String")]
-pub struct SplitCommand(Vec<String>);
+pub struct RegexPattern(regex::Regex);
+
+impl TryFrom<String> for RegexPattern {
+    type Error = regex::Error;
+
+    fn try_from(string: String) -> Result<Self, Self::Error> {
+        Ok(Self(regex::Regex::new(&string)?))
+    }
+}
+
+impl RegexPattern {
+    pub fn is_match(&self, haystack: &str) -> bool {
+        self.0.is_match(haystack)
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "String")]
+pub struct GlobPattern(glob::Pattern);
+
+impl TryFrom<String> for GlobPattern {
+    type Error = glob::PatternError;
+
+    fn try_from(string: String) -> Result<Self, Self::Error> {
+        Ok(Self(glob::Pattern::new(&string)?))
+    }
+}
+
+impl GlobPattern {
+    pub fn matches(&self, string: &str) -> bool {
+        self.0.matches(string)
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub enum SplitCommand {
+    // An empty command, discarding events without executing anything
+    Noop,
+    Exec(Vec<String>),
+}
 
 impl TryFrom<String> for SplitCommand {
     type Error = &'static str;
@@ -167,34 +817,775 @@ impl TryFrom<String> for SplitCommand {
     fn try_from(string: String) -> Result<Self, Self::Error> {
         let command = shell_words::split(&string).map_err(|_| "missing closing quote")?;
         if command.is_empty() {
-            return Err("command is empty");
+            return Ok(Self::Noop);
         }
-        Ok(Self(command))
+        Ok(Self::Exec(command.into_iter().map(expand_tilde).collect()))
+    }
+}
+
+// Expand a leading ~/ to $HOME, leaving it be if HOME is unset
+fn expand_tilde(word: String) -> String {
+    expand_tilde_in(word, std::env::var("HOME").ok().as_deref())
+}
+
+fn expand_tilde_in(word: String, home: Option<&str>) -> String {
+    match (word.strip_prefix("~/"), home) {
+        (Some(rest), Some(home)) => format!("{}/{}", home.trim_end_matches('/'), rest),
+        _ => word,
     }
 }
 
 impl SplitCommand {
+    // Noop commands are never executed, and fail to spawn if they are
     pub fn as_tokio_command(&self) -> Command {
-        let mut command = Command::new(&self.0[0]);
-        if self.0.len() > 1 {
-            command.args(&self.0[1..]);
+        match self {
+            Self::Noop => Command::new(""),
+            Self::Exec(args) => {
+                let mut command = Command::new(&args[0]);
+                command.args(&args[1..]);
+                command
+            }
         }
-        command
     }
 
     pub fn get_prog(&self) -> &str {
-        &self.0[0]
+        match self {
+            Self::Noop => "",
+            Self::Exec(args) => &args[0],
+        }
+    }
+
+    pub fn is_noop(&self) -> bool {
+        matches!(self, Self::Noop)
+    }
+
+    // The interpreter named by a script's shebang line, defaulting to /bin/sh
+    pub fn from_shebang(script: &str) -> Self {
+        script
+            .lines()
+            .next()
+            .and_then(|line| line.strip_prefix("#!"))
+            .map(|line| {
+                line.split_whitespace()
+                    .map(String::from)
+                    .collect::<Vec<_>>()
+            })
+            .filter(|args| !args.is_empty())
+            .map_or_else(|| Self::Exec(vec!["/bin/sh".to_string()]), Self::Exec)
+    }
+
+    // Searching the given PATH, or the server's own
+    pub fn find_prog(&self, path: Option<&str>) -> Option<PathBuf> {
+        if self.is_noop() {
+            return None;
+        }
+
+        let prog = Path::new(self.get_prog());
+        if prog.components().count() > 1 {
+            return prog.is_file().then(|| prog.to_path_buf());
+        }
+
+        let paths = path
+            .map(OsString::from)
+            .or_else(|| std::env::var_os("PATH"));
+        paths.and_then(|paths| {
+            std::env::split_paths(&paths)
+                .map(|dir| dir.join(prog))
+                .find(|path| path.is_file())
+        })
     }
 }
 
 impl Config {
     pub fn from_path<P>(path: P) -> Result<Config>
     where
-        P: AsRef<std::path::Path>,
+        P: AsRef<Path>,
     {
-        Ok(toml::from_str(&read_restrict::read_to_string(
-            path,
-            1024 * 1024,
-        )?)?)
+        let contents = read_restrict::read_to_string(path, 1024 * 1024)?;
+        Self::from_toml(&contents)
+    }
+
+    pub fn from_toml(contents: &str) -> Result<Config> {
+        let mut raw: toml::Table = toml::from_str(contents)?;
+        let version = match raw.remove("version") {
+            Some(version) => version
+                .as_integer()
+                .and_then(|version| u32::try_from(version).ok())
+                .filter(|version| *version > 0)
+                .context("version must be a positive integer")?,
+            None => 1,
+        };
+        if version > CONFIG_VERSION {
+            anyhow::bail!(
+                "config version {} is newer than the supported version {}",
+                version,
+                CONFIG_VERSION
+            );
+        }
+
+        let original = raw.clone();
+        for migrate in &MIGRATIONS[version as usize - 1..] {
+            migrate(&mut raw);
+        }
+
+        // Parse the text where possible, as errors from it include locations
+        let parsed = if raw == original {
+            toml::from_str(contents).map_err(anyhow::Error::from)
+        } else {
+            toml::Value::Table(raw.clone())
+                .try_into()
+                .map_err(anyhow::Error::from)
+        };
+        let mut config: Config = match parsed {
+            Ok(config) => config,
+            Err(error) => {
+                let errors = handler_errors(&raw);
+                if errors.len() > 1 {
+                    anyhow::bail!("{}", errors.join(", "));
+                }
+                return Err(error);
+            }
+        };
+
+        let sanitized = config.log.sanitized_fields();
+        for (index, handler) in config.handler.iter_mut().enumerate() {
+            handler.index = index;
+            handler.sanitize_env = sanitized.clone();
+            if config.user_normalize {
+                handler.user = handler.user.take().map(|user| user.to_lowercase());
+            }
+            if let Some(folder) = &handler.folder {
+                let mode = handler.folder_match_mode.unwrap_or_default();
+                match FolderMatcher::new(mode, folder) {
+                    Ok(matcher) => handler.folder_matcher = Some(matcher),
+                    Err(error) => config.load_errors.push(format!(
+                        "{}: invalid folder {}: {:#}",
+                        handler.label(),
+                        mode,
+                        error
+                    )),
+                }
+            }
+            if let (Some(state_dir), Some(name)) = (&config.state_dir, &handler.name) {
+                handler.state = Some(StateStore::new(state_dir, name));
+            }
+            if let Some(path) = &handler.user_list_file {
+                match UserList::load(path, config.user_normalize) {
+                    Ok(list) => handler.user_list = Some(Arc::new(list)),
+                    Err(error) => {
+                        config
+                            .load_errors
+                            .push(format!("{}: {:#}", handler.label(), error))
+                    }
+                }
+            }
+        }
+
+        Ok(config)
+    }
+
+    pub fn listen_addr(&self) -> std::net::SocketAddr {
+        self.listen
+            .unwrap_or_else(|| std::net::SocketAddr::from(([127, 0, 0, 1], 12525)))
+    }
+
+    // The concurrency limit, which a u16 always fits
+    pub fn max_connections(&self) -> usize {
+        usize::from(self.max_connections.map_or(8, NonZeroU16::get))
+    }
+
+    // Likely mistakes which don't prevent running, errors with --strict
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = vec![];
+
+        // Many handlers for the same user and event suggests a copy and paste
+        // error rather than deliberate fan-out
+        let mut fan_out: HashMap<(&str, &str), usize> = HashMap::new();
+        for handler in self.handler.iter().filter(|handler| !handler.disable) {
+            *fan_out
+                .entry((handler.user_or_pattern(), handler.event_or_pattern()))
+                .or_default() += 1;
+        }
+        let mut fan_out: Vec<_> = fan_out
+            .into_iter()
+            .filter(|(_, count)| *count > 10)
+            .collect();
+        fan_out.sort();
+        for ((user, event), count) in fan_out {
+            warnings.push(format!(
+                "{} handlers match user {} and event {}",
+                count, user, event
+            ));
+        }
+
+        if matches!(&self.allow, Some(allow) if allow.is_empty()) {
+            warnings.push("allow is empty, denying all notifications".to_string());
+        }
+
+        if self.log.timestamp_format.is_some() && !self.log.timestamp {
+            warnings.push("log.timestamp_format has no effect without log.timestamp".to_string());
+        }
+
+        for group in &self.rate_limit_group {
+            if group.limit_period() < Duration::from_secs(1) {
+                warnings.push(format!(
+                    "rate_limit_group {}: limit_period below 1s may not be enforced precisely due to timer resolution",
+                    group.name
+                ));
+            }
+        }
+
+        for (index, handler) in self.handler.iter().enumerate() {
+            if matches!(&handler.user, Some(user) if user.trim().is_empty()) {
+                warnings.push(format!("{}: empty user can never match", handler.label()));
+            }
+
+            // Logging isn't set up yet when commands are parsed
+            let commands = [
+                &handler.command,
+                &handler.pre_command,
+                &handler.post_command,
+                &handler.notify_on_rate_limit,
+                &handler.on_error_command,
+            ];
+            for command in commands.into_iter().flatten() {
+                if let SplitCommand::Exec(args) = command {
+                    if args.iter().any(|arg| arg.starts_with("~/")) {
+                        warnings.push(format!(
+                            "{}: HOME is unset, not expanding ~/ in {}",
+                            handler.label(),
+                            args[0]
+                        ));
+                    }
+                }
+            }
+
+            if handler.rate_limit_group.is_none()
+                && handler.limit_burst().is_some()
+                && handler.limit_period() < Duration::from_secs(1)
+            {
+                warnings.push(format!(
+                    "{}: limit_period below 1s may not be enforced precisely due to timer resolution",
+                    handler.label()
+                ));
+            }
+
+            // Handlers sharing user, event and folder may be deliberate fan-out,
+            // but are more likely a mistake if they also run the same thing
+            for other in &self.handler[..index] {
+                if !other.disable
+                    && !handler.disable
+                    && other.event_or_pattern() == handler.event_or_pattern()
+                    && other.user == handler.user
+                    && other.folder == handler.folder
+                    && other.folder_match_mode == handler.folder_match_mode
+                    && other.user_pattern.as_ref().map(RegexPattern::as_str)
+                        == handler.user_pattern.as_ref().map(RegexPattern::as_str)
+                    && other.user_list_file == handler.user_list_file
+                {
+                    if other.command == handler.command && other.script == handler.script {
+                        warnings.push(format!(
+                            "{}: duplicates {}, running the same command",
+                            handler.label(),
+                            other.label()
+                        ));
+                    } else {
+                        warnings.push(format!(
+                            "{}: matches the same user, event and folder as {}",
+                            handler.label(),
+                            other.label()
+                        ));
+                    }
+                }
+            }
+        }
+
+        warnings
+    }
+
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = self.load_errors.clone();
+
+        // Invalid routes make axum panic during startup
+        if let Some(endpoint) = &self.endpoint {
+            if !endpoint.starts_with('/') {
+                errors.push(format!("endpoint must start with '/': {}", endpoint));
+            }
+            if endpoint.contains(['?', '#']) {
+                errors.push(format!(
+                    "endpoint must not contain '?' or '#': {}",
+                    endpoint
+                ));
+            }
+            if endpoint.contains("//") {
+                errors.push(format!("endpoint must not contain '//': {}", endpoint));
+            }
+            if endpoint == "/config/allow" {
+                errors.push("endpoint must not be /config/allow".to_string());
+            }
+        }
+
+        if matches!(self.max_body_bytes, Some(max) if max < 256) {
+            errors.push("max_body_bytes must be at least 256".to_string());
+        }
+
+        if let Some(tls) = &self.tls {
+            if tls.cert.is_some() == tls.cert_pem.is_some() {
+                errors.push("tls requires exactly one of cert or cert_pem".to_string());
+            }
+            if tls.key.is_some() == tls.key_pem.is_some() {
+                errors.push("tls requires exactly one of key or key_pem".to_string());
+            }
+            if tls.http2_only && self.http_version == HttpVersion::Http1 {
+                errors.push("tls.http2_only conflicts with http_version http1".to_string());
+            }
+        } else if self.http_version == HttpVersion::Http2 {
+            // axum-server has no support for h2c, HTTP/2 over cleartext
+            errors.push("http_version http2 requires tls".to_string());
+        }
+
+        if let Some(auth) = &self.auth {
+            match (&auth.htpasswd_file, &auth.user, &auth.pass) {
+                (Some(_), None, None) | (None, Some(_), Some(_)) => (),
+                (Some(_), _, _) => errors.push(
+                    "auth.htpasswd_file is mutually exclusive with user and pass".to_string(),
+                ),
+                _ => errors.push("auth requires user and pass, or htpasswd_file".to_string()),
+            }
+        }
+
+        if let Some(realm) = self.auth.as_ref().and_then(|auth| auth.realm.as_ref()) {
+            if realm.contains('"') || !realm.chars().all(|c| c == ' ' || c.is_ascii_graphic()) {
+                errors.push("auth.realm must be printable ASCII without quotes".to_string());
+            }
+        }
+
+        for path in self.log.health_check_paths() {
+            if !path.starts_with('/') {
+                errors.push(format!(
+                    "log.health_check_paths must start with '/': {}",
+                    path
+                ));
+            }
+        }
+
+        for directive in self.log.target_directives() {
+            if let Err(error) = directive.parse::<tracing_subscriber::filter::Directive>() {
+                errors.push(format!("log.target_filter {}: {}", directive, error));
+            }
+        }
+
+        if let Some(format) = &self.log.timestamp_format {
+            if chrono::format::StrftimeItems::new(format)
+                .any(|item| matches!(item, chrono::format::Item::Error))
+            {
+                errors.push(format!("log.timestamp_format is invalid: {}", format));
+            }
+        }
+
+        // hyper panics on a read buffer smaller than this
+        if matches!(self.max_header_bytes, Some(max) if max < 8192) {
+            errors.push("max_header_bytes must be at least 8192".to_string());
+        }
+
+        for (index, group) in self.rate_limit_group.iter().enumerate() {
+            let label = format!("rate_limit_group {}", group.name);
+            if self.rate_limit_group[..index]
+                .iter()
+                .any(|other| other.name == group.name)
+            {
+                errors.push(format!("{}: duplicate name", label));
+            }
+            errors.extend(limit_errors(
+                &label,
+                group.limit_period(),
+                group.limit_burst(),
+            ));
+        }
+
+        for (index, handler) in self.handler.iter().enumerate() {
+            errors.extend(handler.validate());
+            if let Some(name) = &handler.name {
+                if self.handler[..index]
+                    .iter()
+                    .any(|other| other.name.as_ref() == Some(name))
+                {
+                    errors.push(format!("{}: duplicate name", handler.label()));
+                }
+                // State files are named after their handler
+                if self.state_dir.is_some()
+                    && Path::new(name).file_name() != Some(std::ffi::OsStr::new(name))
+                {
+                    errors.push(format!(
+                        "{}: name must be a plain file name with state_dir",
+                        handler.label()
+                    ));
+                }
+            }
+            if let Some(group) = &handler.rate_limit_group {
+                if !self
+                    .rate_limit_group
+                    .iter()
+                    .any(|other| &other.name == group)
+                {
+                    errors.push(format!(
+                        "{}: rate_limit_group {} does not exist",
+                        handler.label(),
+                        group
+                    ));
+                }
+            }
+        }
+
+        errors
+    }
+}
+
+// Catch durations which would overflow inside the rate limiter, or be too
+// short for timers to enforce
+fn limit_errors(label: &str, period: Duration, burst: NonZeroU32) -> Vec<String> {
+    let mut errors = vec![];
+    if period < Duration::from_millis(100) {
+        errors.push(format!(
+            "{}: limit_period {} is below the minimum of 100ms",
+            label,
+            humantime::format_duration(period)
+        ));
+    }
+    if Quota::with_period(period).is_none() || period.checked_mul(burst.get()).is_none() {
+        errors.push(format!(
+            "{}: limit_period {} is out of range for limit_burst {}",
+            label,
+            humantime::format_duration(period),
+            burst
+        ));
+    }
+    errors
+}
+
+impl Handler {
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = vec![];
+        let label = self.label();
+
+        if self.user_list_file.is_none() && self.user.is_some() == self.user_pattern.is_some() {
+            errors.push(format!(
+                "{}: exactly one of user or user_pattern is required",
+                label
+            ));
+        } else if self.user.is_some() && self.user_pattern.is_some() {
+            errors.push(format!(
+                "{}: user and user_pattern are mutually exclusive",
+                label
+            ));
+        }
+
+        if self.event.is_some() && self.event_pattern.is_some() {
+            errors.push(format!(
+                "{}: event and event_pattern are mutually exclusive",
+                label
+            ));
+        }
+
+        if self.event_coalesce
+            && (self.event_pattern.is_some()
+                || matches!(self.event, Some(event) if event != ImseEvent::MessageNew))
+        {
+            errors.push(format!(
+                "{}: event_coalesce matches every event, and conflicts with event and event_pattern",
+                label
+            ));
+        }
+
+        if self.periodic_align && self.periodic.is_none() {
+            errors.push(format!("{}: periodic_align requires periodic", label));
+        }
+
+        if self.periodic_jitter.is_some() && self.periodic.is_none() {
+            errors.push(format!("{}: periodic_jitter requires periodic", label));
+        }
+
+        if self.periodic_immediate && self.periodic.is_none() {
+            errors.push(format!("{}: periodic_immediate requires periodic", label));
+        }
+
+        if self.periodic_always_fresh && self.periodic.is_none() {
+            errors.push(format!(
+                "{}: periodic_always_fresh requires periodic",
+                label
+            ));
+        }
+
+        if self.dedup_ttl.is_some() && !self.dedup_by_uid {
+            errors.push(format!("{}: dedup_ttl requires dedup_by_uid", label));
+        }
+
+        if self.fan_in_window.is_some() && self.delay.is_some() {
+            errors.push(format!(
+                "{}: fan_in_window and delay are mutually exclusive",
+                label
+            ));
+        }
+
+        if self.fan_in_min_events.is_some() && self.fan_in_window.is_none() {
+            errors.push(format!(
+                "{}: fan_in_min_events requires fan_in_window",
+                label
+            ));
+        }
+
+        if self.folder_match_mode.is_some() && self.folder.is_none() {
+            errors.push(format!("{}: folder_match_mode requires folder", label));
+        }
+
+        if self.command.is_some() == self.script.is_some() {
+            errors.push(format!(
+                "{}: requires exactly one of command or script",
+                label
+            ));
+        }
+
+        for command in self
+            .pre_command
+            .iter()
+            .chain(&self.post_command)
+            .chain(&self.notify_on_rate_limit)
+            .chain(&self.on_error_command)
+        {
+            if command.is_noop() {
+                errors.push(format!("{}: only command may be empty", label));
+            }
+        }
+
+        let interpreter = self.script.as_deref().map(SplitCommand::from_shebang);
+        for command in self
+            .command
+            .iter()
+            .filter(|command| !command.is_noop())
+            .chain(&interpreter)
+            .chain(&self.pre_command)
+            .chain(&self.post_command)
+            .chain(&self.notify_on_rate_limit)
+            .chain(&self.on_error_command)
+        {
+            if command.find_prog(self.command_path()).is_none() {
+                errors.push(format!(
+                    "{}: command not found: {}",
+                    label,
+                    command.get_prog()
+                ));
+            }
+        }
+
+        if let Some(template) = &self.command_stdin_template {
+            if let Err(error) = tera::Tera::default().add_raw_template("stdin", template) {
+                errors.push(format!("{}: command_stdin_template: {}", label, error));
+            }
+        }
+
+        if let Some(templates) = &self.command_args_template {
+            if self.command.as_ref().is_none_or(SplitCommand::is_noop) {
+                errors.push(format!("{}: command_args_template requires command", label));
+            }
+            for template in templates {
+                if let Err(error) = tera::Tera::default().add_raw_template("args", template) {
+                    errors.push(format!("{}: command_args_template: {}", label, error));
+                }
+            }
+        }
+
+        if let Some(path) = &self.wasm_filter {
+            #[cfg(feature = "wasm")]
+            if let Err(error) = crate::wasm::check(path) {
+                errors.push(format!(
+                    "{}: wasm_filter {}: {}",
+                    label,
+                    path.display(),
+                    error
+                ));
+            }
+
+            #[cfg(not(feature = "wasm"))]
+            errors.push(format!(
+                "{}: wasm_filter {} requires building with the wasm feature",
+                label,
+                path.display()
+            ));
+        }
+
+        if self.command_stdin_template.is_some() && self.stdin_file.is_some() {
+            errors.push(format!(
+                "{}: command_stdin_template and stdin_file are mutually exclusive",
+                label
+            ));
+        }
+
+        if self.kill_grace_period.is_some() && self.command_timeout.is_none() {
+            errors.push(format!(
+                "{}: kill_grace_period requires command_timeout",
+                label
+            ));
+        }
+
+        if let Some(path) = &self.path {
+            if std::env::split_paths(path).all(|dir| dir.as_os_str().is_empty()) {
+                errors.push(format!(
+                    "{}: path must contain at least one directory",
+                    label
+                ));
+            }
+        }
+
+        if self.output_on_failure_only && !self.capture_output {
+            errors.push(format!(
+                "{}: output_on_failure_only requires capture_output",
+                label
+            ));
+        }
+
+        if self.catchup {
+            if self.periodic.is_none() {
+                errors.push(format!("{}: catchup requires periodic", label));
+            }
+            if self.state.is_none() {
+                errors.push(format!("{}: catchup requires a name and state_dir", label));
+            }
+        }
+
+        if self.rate_limit_group.is_some() {
+            if self.limit_period.is_some()
+                || self.limit_burst.is_some()
+                || self.rate_limit_mode.is_some()
+            {
+                errors.push(format!(
+                    "{}: rate_limit_group is mutually exclusive with limit_period, limit_burst and rate_limit_mode",
+                    label
+                ));
+            }
+        } else if let Some(burst) = self.limit_burst() {
+            errors.extend(limit_errors(&label, self.limit_period(), burst));
+        }
+
+        errors
+    }
+
+    // The PATH commands are run with, if not the server's own
+    pub fn command_path(&self) -> Option<&str> {
+        self.path
+            .as_deref()
+            .or(self.clear_env.then_some(CLEAR_ENV_PATH))
+    }
+
+    // Listed users match in addition to user or user_pattern
+    pub fn matches_user(&self, user: &str) -> bool {
+        if let Some(list) = &self.user_list {
+            if list.contains(user) {
+                return true;
+            }
+        }
+
+        match (&self.user, &self.user_pattern) {
+            (Some(expected), _) => expected == user,
+            (None, Some(pattern)) => pattern.is_match(user),
+            (None, None) => false,
+        }
+    }
+
+    // Patterns match the PascalCase event name, as used in configuration
+    pub fn matches_event(&self, event: ImseEvent) -> bool {
+        if self.event_coalesce {
+            return true;
+        }
+
+        match &self.event_pattern {
+            Some(pattern) => pattern.matches(&event.to_string()),
+            None => self.event.unwrap_or_default() == event,
+        }
+    }
+
+    pub fn event_or_pattern(&self) -> &str {
+        match &self.event_pattern {
+            Some(pattern) => pattern.as_str(),
+            None => self.event.unwrap_or_default().to_kebab_case(),
+        }
+    }
+
+    // The first field the handler requires which the message lacks
+    pub fn missing_required(&self, message: &ImseMessage) -> Option<&'static str> {
+        let absent = |field: &Option<String>| field.as_deref().is_none_or(str::is_empty);
+        if self.snippet_required && absent(&message.snippet) {
+            Some("snippet")
+        } else if self.from_required && absent(&message.from) {
+            Some("from")
+        } else {
+            None
+        }
+    }
+
+    pub fn matches_folder(&self, folder: &str) -> bool {
+        self.folder_matcher
+            .as_ref()
+            .is_none_or(|matcher| matcher.is_match(folder))
+    }
+
+    pub fn user_or_pattern(&self) -> &str {
+        match (&self.user, &self.user_pattern) {
+            (Some(user), _) => user.as_str(),
+            (None, Some(pattern)) => pattern.as_str(),
+            (None, None) => "",
+        }
+    }
+
+    pub fn has_any_tag(&self, tags: &[String]) -> bool {
+        self.tags
+            .as_ref()
+            .is_some_and(|own| own.iter().any(|tag| tags.contains(tag)))
+    }
+
+    pub fn prog(&self) -> &str {
+        self.command
+            .as_ref()
+            .map_or("script", SplitCommand::get_prog)
+    }
+
+    pub fn label(&self) -> String {
+        match &self.name {
+            Some(name) => format!("handler[{}] ({})", self.index, name),
+            None => format!("handler[{}]", self.index),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn max_connections() {
+        let parse = |max: &str| Config::from_toml(&format!("{}\nhandler = []", max));
+        assert_eq!(parse("").unwrap().max_connections(), 8);
+        assert_eq!(
+            parse("max_connections = 65535").unwrap().max_connections(),
+            65535
+        );
+        assert!(parse("max_connections = 65536").is_err());
+        assert!(parse("max_connections = 0").is_err());
+    }
+
+    #[test]
+    fn expand_tilde_home() {
+        let home = Some("/home/user");
+        assert_eq!(
+            expand_tilde_in("~/bin/test.sh".into(), home),
+            "/home/user/bin/test.sh"
+        );
+        assert_eq!(
+            expand_tilde_in("bin/~/test.sh".into(), home),
+            "bin/~/test.sh"
+        );
+        assert_eq!(
+            expand_tilde_in("~/bin/test.sh".into(), None),
+            "~/bin/test.sh"
+        );
     }
 }
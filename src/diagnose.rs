@@ -0,0 +1,206 @@
+use anyhow::{anyhow, bail, Context, Result};
+use base64::Engine;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::{timeout, Duration},
+};
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use crate::config::Config;
+
+struct Check {
+    name: String,
+    result: Result<String>,
+}
+
+// Exercises the configuration against the running system, printing a line
+// per check and failing if any do
+pub async fn diagnose(config: Config, errors: Vec<String>) -> Result<()> {
+    let mut checks = vec![];
+    let addr = config.listen_addr();
+
+    checks.push(Check {
+        name: "config".to_string(),
+        result: if errors.is_empty() {
+            Ok("valid".to_string())
+        } else {
+            Err(anyhow!(errors.join(", ")))
+        },
+    });
+
+    // Kept for the loopback server, so it can't race another instance for
+    // the address
+    let bound = match &config.bind_device {
+        Some(device) => crate::bind_device(addr, device),
+        None => std::net::TcpListener::bind(addr)
+            .and_then(|listener| listener.set_nonblocking(true).map(|()| listener))
+            .map_err(Into::into),
+    };
+    let (listener, result) = match bound {
+        Ok(listener) => (
+            Some(listener),
+            Ok(config
+                .bind_device
+                .as_ref()
+                .map_or_else(|| "ok".to_string(), |device| format!("device {}", device))),
+        ),
+        Err(error) => (None, Err(error)),
+    };
+    checks.push(Check {
+        name: format!("bind {}", addr),
+        result,
+    });
+
+    if let Some(tls) = &config.tls {
+        checks.push(Check {
+            name: "tls".to_string(),
+            result: crate::tls::server_config(tls, config.http_version)
+                .await
+                .map(|_| "certificate and key loaded".to_string()),
+        });
+    }
+
+    checks.push(Check {
+        name: "loopback notify".to_string(),
+        // Passwords in an htpasswd file are hashed, so can't be sent
+        result: match (listener, &config.tls, &config.auth) {
+            // Serving an invalid configuration may panic
+            _ if !errors.is_empty() => Ok("skipped with an invalid config".to_string()),
+            (None, _, _) => Ok("skipped without the listen address".to_string()),
+            (_, Some(_), _) => Ok("skipped with tls".to_string()),
+            (_, _, Some(auth)) if auth.htpasswd_file.is_some() => {
+                Ok("skipped with htpasswd_file auth".to_string())
+            }
+            (Some(listener), _, _) => loopback(&config, listener).await,
+        },
+    });
+
+    let interpreters: Vec<_> = config
+        .handler
+        .iter()
+        .map(|handler| {
+            handler
+                .script
+                .as_deref()
+                .map(crate::config::SplitCommand::from_shebang)
+        })
+        .collect();
+    for (handler, interpreter) in config.handler.iter().zip(&interpreters) {
+        for command in handler
+            .command
+            .iter()
+            .chain(interpreter)
+            .chain(&handler.pre_command)
+            .chain(&handler.post_command)
+            .chain(&handler.notify_on_rate_limit)
+            .chain(&handler.on_error_command)
+            .filter(|command| !command.is_noop())
+        {
+            checks.push(Check {
+                name: format!("{} {}", handler.label(), command.get_prog()),
+                result: executable(command.find_prog(handler.command_path())),
+            });
+        }
+    }
+
+    let mut failed = 0;
+    for check in &checks {
+        match &check.result {
+            Ok(detail) => println!("PASS  {:<40} {}", check.name, detail),
+            Err(error) => {
+                failed += 1;
+                println!("FAIL  {:<40} {:#}", check.name, error);
+            }
+        }
+    }
+
+    if failed > 0 {
+        bail!("{} of {} checks failed", failed, checks.len());
+    }
+    Ok(())
+}
+
+// Serve the configuration without handlers, so nothing is executed, and
+// send it a synthetic event
+async fn loopback(config: &Config, listener: std::net::TcpListener) -> Result<String> {
+    let addr = listener.local_addr()?;
+    let mut config = config.clone();
+    config.handler.clear();
+    config.sync_response = false;
+
+    let target = match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => {
+            SocketAddr::from((Ipv4Addr::LOCALHOST, addr.port()))
+        }
+        IpAddr::V6(ip) if ip.is_unspecified() => {
+            SocketAddr::from((Ipv6Addr::LOCALHOST, addr.port()))
+        }
+        _ => addr,
+    };
+
+    // The server stops when its future is dropped after the request completes
+    tokio::select! {
+        result = crate::run(config.clone(), Some(listener)) => {
+            result.and_then(|()| Err(anyhow!("server exited unexpectedly")))
+        }
+        result = timeout(Duration::from_secs(5), put_event(&config, target)) => {
+            result.unwrap_or_else(|_| Err(anyhow!("timed out")))
+        }
+    }
+}
+
+async fn put_event(config: &Config, target: SocketAddr) -> Result<String> {
+    // Accepted once the server starts, as it's already listening
+    let mut stream = TcpStream::connect(target)
+        .await
+        .with_context(|| format!("connecting to {}", target))?;
+
+    let body = r#"{"event":"MessageNew","user":"imserious-diagnose","unseen":0,"folder":"INBOX"}"#;
+    let authorization = match config.auth.as_ref().map(|auth| (&auth.user, &auth.pass)) {
+        Some((Some(user), Some(pass))) => format!(
+            "Authorization: Basic {}\r\n",
+            base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", user, pass))
+        ),
+        _ => String::new(),
+    };
+    let request = format!(
+        "PUT {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n{}",
+        config.endpoint.as_deref().unwrap_or("/notify"),
+        target,
+        body.len(),
+        authorization,
+        body
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut response = vec![];
+    stream.read_to_end(&mut response).await?;
+    let status = String::from_utf8_lossy(&response)
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .to_string();
+
+    if status.contains(" 202 ") || status.contains(" 200 ") {
+        Ok(status)
+    } else {
+        Err(anyhow!("unexpected response: {}", status))
+    }
+}
+
+fn executable(path: Option<std::path::PathBuf>) -> Result<String> {
+    let path = path.context("not found")?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(&path)?.permissions().mode();
+        if mode & 0o111 == 0 {
+            bail!("{} is not executable", path.display());
+        }
+    }
+
+    Ok(path.display().to_string())
+}
@@ -1,13 +1,51 @@
-use governor::{Quota, RateLimiter};
-use nonzero_ext::nonzero;
+use rand::Rng;
 use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader},
+    process::{Child, Command},
     sync::watch,
     time::{timeout_at, Duration, Instant},
 };
+use tracing::Level;
 
-use std::sync::Arc;
+use std::{
+    borrow::Cow,
+    collections::{hash_map::Entry, HashMap, HashSet},
+    io::Write,
+    num::NonZeroU32,
+    process::{ExitStatus, Stdio},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    config::{Handler, LoggingLevel, SplitCommand},
+    limiter::KeyedLimiter,
+    message::{Completion, ImseEvent, ImseMessage},
+};
+
+// tracing levels must be constant, so select between macros at runtime
+macro_rules! event_at {
+    ($level:expr, $($args:tt)+) => {
+        match $level {
+            Level::ERROR => tracing::error!(target: "imserious::handler", $($args)+),
+            Level::WARN => tracing::warn!(target: "imserious::handler", $($args)+),
+            Level::INFO => tracing::info!(target: "imserious::handler", $($args)+),
+            Level::DEBUG => tracing::debug!(target: "imserious::handler", $($args)+),
+            _ => tracing::trace!(target: "imserious::handler", $($args)+),
+        }
+    };
+}
 
-use crate::{config::Handler, message::ImseMessage};
+// Message fields in ImseMessage::to_json given their own IMSE_* variables
+const ENV_FIELDS: &[&str] = &[
+    "event",
+    "user",
+    "unseen",
+    "folder",
+    "mailboxId",
+    "from",
+    "snippet",
+];
 
 pub type HandlerPayload = Option<Arc<ImseMessage>>;
 pub type HandlerSender = watch::Sender<HandlerPayload>;
@@ -18,26 +56,126 @@ impl Handler {
             .periodic
             .map_or(Duration::from_secs(3600), Duration::from);
         let mut latest: HandlerPayload = None;
+        let mut last_seen: HandlerPayload = None;
+        let mut batch: Vec<Arc<ImseMessage>> = vec![];
+
+        // UIDs are only unique within a folder
+        let dedup_ttl = self
+            .dedup_ttl
+            .map_or(Duration::from_secs(60), Duration::from);
+        let mut seen_uids: HashMap<(String, String, u32), Instant> = HashMap::new();
         let mut now = Instant::now();
         let mut last_burst = now;
         let mut deadline = now + period;
 
-        let quota = Quota::with_period(
-            self.limit_period
-                .map_or(Duration::from_secs(30), Duration::from),
-        )
-        .expect("Non-zero Duration")
-        .allow_burst(self.limit_burst.unwrap_or(nonzero!(1u32)));
-        let clock = governor::clock::MonotonicClock;
-        let limiter = RateLimiter::direct_with_clock(quota, &clock);
+        // Align to the next multiple of the period since the Unix epoch, i.e.
+        // UTC boundaries like the top of the hour or midnight
+        if self.periodic_align {
+            let since_epoch = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos();
+            let period_nanos = period.as_nanos();
+            let remaining = period_nanos - since_epoch % period_nanos;
+            deadline = now + Duration::from_nanos(remaining as u64);
+        }
+
+        // Stagger the first periodic execution, later periods follow on from it
+        if let Some(jitter) = self.periodic_jitter {
+            let jitter_millis = jitter.into_std().as_millis().max(1) as u64;
+            deadline += Duration::from_millis(rand::thread_rng().gen_range(0..jitter_millis));
+        }
+
+        // Run once at startup, later periods follow on from it
+        if self.periodic_immediate {
+            deadline = now;
+        }
+
+        // Without rate_limit_per_folder, every event shares the same key.
+        // Handlers in a rate_limit_group share its limiter instead of their own
+        let limiter = self.shared_limiter.clone().or_else(|| {
+            self.limit_burst().map(|burst| {
+                Arc::new(Mutex::new(KeyedLimiter::new(
+                    self.rate_limit_mode.unwrap_or_default(),
+                    self.limit_period(),
+                    burst,
+                )))
+            })
+        });
+
+        #[cfg(feature = "wasm")]
+        let mut filter = self
+            .wasm_filter
+            .as_deref()
+            .map(crate::wasm::WasmFilter::new);
+
+        if self.catchup {
+            self.run_catchup(period).await;
+        }
 
         while let Ok(event) = timeout_at(deadline, rx.changed()).await.ok().transpose() {
             now = Instant::now();
+            let mut periodic_message = None;
             if event.is_some() {
-                if latest.is_none() {
+                let message = rx.borrow_and_update().clone();
+
+                #[cfg(feature = "wasm")]
+                if let (Some(filter), Some(message)) = (&mut filter, &message) {
+                    if !filter.should_handle(message) {
+                        tracing::debug!(user=%message.user, event=%message.event.to_kebab_case(), "wasm_filtered");
+                        signal_completion(
+                            self.index,
+                            &Some(Arc::clone(message)),
+                            &[],
+                            Completion::Skipped,
+                        );
+                        continue;
+                    }
+                }
+
+                if let (true, Some(message)) = (self.dedup_by_uid, &message) {
+                    if let Some(uid) = message.message_uid {
+                        seen_uids.retain(|_, seen| now.duration_since(*seen) < dedup_ttl);
+                        let key = (message.user.clone(), message.folder.clone(), uid);
+                        match seen_uids.entry(key) {
+                            Entry::Occupied(_) => {
+                                tracing::debug!(user=%message.user, event=%message.event.to_kebab_case(), uid, "dedup_by_uid");
+                                signal_completion(
+                                    self.index,
+                                    &Some(Arc::clone(message)),
+                                    &[],
+                                    Completion::Skipped,
+                                );
+                                continue;
+                            }
+                            Entry::Vacant(entry) => {
+                                entry.insert(now);
+                            }
+                        }
+                    }
+                }
+
+                // Delay from the first event of a burst, or the latest if debouncing
+                if latest.is_none() || self.debounce_reset {
                     last_burst = now;
                 }
-                latest = rx.borrow_and_update().clone();
+                last_seen = message.clone();
+                // A batch keeps every message, otherwise the newest replaces any pending
+                if self.fan_in_window.is_none() {
+                    signal_completion(self.index, &latest, &[], Completion::Skipped);
+                }
+                latest = message;
+
+                // Collect events until the window closes or enough have arrived
+                if let (Some(window), Some(message)) = (self.fan_in_window, &latest) {
+                    if batch.is_empty() {
+                        deadline = now + window.into_std();
+                    }
+                    batch.push(Arc::clone(message));
+                    if batch.len() < self.fan_in_min_events.map_or(usize::MAX, |n| n as usize) {
+                        continue;
+                    }
+                }
 
                 if let Some(delay) = self.delay {
                     if let Some(delay) = delay.into_std().checked_sub(last_burst.elapsed()) {
@@ -45,54 +183,526 @@ impl Handler {
                         continue;
                     }
                 }
-            } else if latest.is_none() && self.periodic.is_none() {
+            } else if latest.is_none() {
                 // Ignore periodic wakeups if not configured for them
-                continue;
+                if self.periodic.is_none() {
+                    continue;
+                }
+                // Otherwise pass on the last message seen since the previous one
+                if !self.periodic_always_fresh {
+                    periodic_message = last_seen.take();
+                }
             }
 
             // Let periodic execution ignore rate limits
-            if latest.is_some() {
-                if let Err(not_until) = limiter.check() {
-                    deadline = not_until.earliest_possible().into();
+            if let (Some(limiter), Some(message)) = (&limiter, &latest) {
+                let key = if self.rate_limit_per_folder {
+                    message.folder.as_str()
+                } else {
+                    ""
+                };
+                let checked = limiter.lock().unwrap_or_else(|e| e.into_inner()).check(key);
+                if let Err(not_until) = checked {
+                    // Never wake on an already-expired deadline and spin
+                    let now = Instant::now();
+                    deadline = not_until.max(now + Duration::from_millis(1));
+                    let wait_ms = deadline.duration_since(now).as_millis();
+                    tracing::debug!(user=%self.user_or_pattern(), event=%self.event_or_pattern(), %wait_ms, "rate_limited");
+                    if let Some(notify) = &self.notify_on_rate_limit {
+                        self.notify_rate_limited(notify, &latest, wait_ms);
+                    }
+                    // Reject rather than hold the event, leaving the sender to retry
+                    if self.fail_fast {
+                        signal_completion(self.index, &latest, &batch, Completion::RateLimited);
+                        latest = None;
+                        batch.clear();
+                        deadline = Instant::now() + period;
+                    }
+                    // Otherwise the event is held, and completes once executed
                     continue;
                 }
             }
 
-            self.execute(latest.take()).await;
+            self.execute(
+                latest.take().or(periodic_message),
+                std::mem::take(&mut batch),
+            )
+            .await;
+
+            // Events arriving during execution are held in the channel, mark
+            // them seen so they don't trigger another run
+            if self.skip_if_running && rx.has_changed().unwrap_or(false) {
+                let skipped = rx.borrow_and_update().clone();
+                if let Some(message) = &skipped {
+                    tracing::debug!(user=%message.user, event=%message.event.to_kebab_case(), "skip_if_running");
+                }
+                signal_completion(self.index, &skipped, &[], Completion::Skipped);
+            }
+
+            if self.catchup {
+                self.record_execution().await;
+            }
             deadline = Instant::now() + period;
         }
     }
 
-    #[tracing::instrument(skip_all, fields(event=%self.event, user=%self.user, prog=%self.command.get_prog()))]
-    async fn execute(&self, message: HandlerPayload) {
-        let mut command = self.command.as_tokio_command();
+    async fn run_catchup(&self, period: Duration) {
+        let state = self.state.as_ref().expect("catchup requires state");
+        let last = match state.get("last_executed_at").await {
+            Some(last) => match last.as_u64() {
+                Some(secs) => UNIX_EPOCH + Duration::from_secs(secs),
+                None => {
+                    tracing::warn!(?last, "catchup");
+                    return;
+                }
+            },
+            None => return,
+        };
+
+        let elapsed = SystemTime::now().duration_since(last).unwrap_or_default();
+        let missed = std::cmp::min(
+            elapsed.as_nanos() / period.as_nanos(),
+            u128::from(self.max_catchup.unwrap_or(10)),
+        ) as u32;
+
+        tracing::info!(user=%self.user_or_pattern(), event=%self.event_or_pattern(), ?elapsed, missed, "catchup");
+        for _ in 0..missed {
+            self.execute(None, vec![]).await;
+        }
+        if missed > 0 {
+            self.record_execution().await;
+        }
+    }
+
+    async fn record_execution(&self) {
+        let state = self.state.as_ref().expect("catchup requires state");
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        if let Err(error) = state.set("last_executed_at", now.into()).await {
+            tracing::warn!(%error, "record_execution");
+        }
+    }
+
+    #[tracing::instrument(skip_all, fields(event=%self.event_or_pattern(), user=%self.user_or_pattern(), prog=%self.prog()))]
+    async fn execute(&self, message: HandlerPayload, batch: Vec<Arc<ImseMessage>>) {
+        if self.command.as_ref().is_some_and(SplitCommand::is_noop) {
+            tracing::debug!(count = batch.len(), "noop");
+            signal_completion(self.index, &message, &batch, Completion::Success);
+            return;
+        }
+
+        // pre_command, command and post_command share one command_timeout
+        let deadline = self
+            .command_timeout
+            .map(|limit| Instant::now() + Duration::from(limit));
+
+        // A pre_command declining to proceed is not a failure
+        let mut proceed = true;
+        let mut failed = false;
+        if let Some(pre_command) = &self.pre_command {
+            let command = self.prepare(pre_command, &message);
+            let (_, success) = self
+                .spawn("pre_command", command, None, deadline, |rc| rc == 0)
+                .await;
+            proceed = success;
+        }
+
+        let mut stdin = None;
+        if let Some(template) = &self.command_stdin_template {
+            match tera::Tera::one_off(template, &self.template_context(&message), false) {
+                Ok(rendered) => stdin = Some(rendered),
+                Err(error) => {
+                    tracing::error!(%error, "command_stdin_template");
+                    proceed = false;
+                    failed = true;
+                }
+            }
+        }
+
+        let mut args = vec![];
+        if let Some(templates) = &self.command_args_template {
+            let context = self.template_context(&message);
+            for template in templates {
+                match tera::Tera::one_off(template, &context, false) {
+                    Ok(arg) => args.push(arg),
+                    Err(error) => {
+                        tracing::error!(%error, "command_args_template");
+                        proceed = false;
+                        failed = true;
+                        break;
+                    }
+                }
+            }
+        }
+
+        let mut stdin_file = None;
+        if let Some(path) = &self.stdin_file {
+            match tokio::fs::File::open(path).await {
+                Ok(file) => stdin_file = Some(file.into_std().await),
+                Err(error) => {
+                    tracing::error!(path=%path.display(), %error, "stdin_file");
+                    proceed = false;
+                    failed = true;
+                }
+            }
+        }
+
+        // Held until execution completes, removing the file on drop
+        let mut script = None;
+        if let Some(content) = &self.script {
+            match write_script(content) {
+                Ok(file) => script = Some(file),
+                Err(error) => {
+                    tracing::error!(%error, "script");
+                    proceed = false;
+                    failed = true;
+                }
+            }
+        }
+
+        if proceed {
+            let mut command = match (&self.command, &script) {
+                (Some(command), _) => {
+                    let mut command = self.prepare(command, &message);
+                    command.args(&args);
+                    command
+                }
+                (None, Some(file)) => {
+                    let interpreter =
+                        SplitCommand::from_shebang(self.script.as_deref().unwrap_or(""));
+                    let mut command = self.prepare(&interpreter, &message);
+                    command.arg(file.path());
+                    command
+                }
+                (None, None) => unreachable!("validated to have command or script"),
+            };
+            if !batch.is_empty() {
+                let json = batch.iter().map(|message| message.to_json()).collect();
+                command
+                    .env(
+                        "IMSE_BATCH_JSON",
+                        serde_json::Value::Array(json).to_string(),
+                    )
+                    .env("IMSE_BATCH_COUNT", batch.len().to_string());
+            }
+            if stdin.is_some() {
+                command.stdin(Stdio::piped());
+            } else if let Some(file) = stdin_file {
+                command.stdin(Stdio::from(file));
+            }
+            let (rc, success) = self
+                .spawn("command", command, stdin, deadline, |rc| {
+                    self.is_success(rc)
+                })
+                .await;
+
+            failed = !success;
+            if let (false, Some(on_error)) = (success, &self.on_error_command) {
+                self.notify_error(on_error, &message, rc);
+            }
+
+            if let Some(post_command) = &self.post_command {
+                let mut command = self.prepare(post_command, &message);
+                command.env("IMSE_MAIN_EXIT_CODE", rc.unwrap_or(-1).to_string());
+                self.spawn("post_command", command, None, deadline, |rc| rc == 0)
+                    .await;
+            }
+        }
+
+        let outcome = if failed {
+            Completion::Failed
+        } else {
+            Completion::Success
+        };
+        signal_completion(self.index, &message, &batch, outcome);
+    }
+
+    // Runs in the background, outside the rate limiter, so it never delays the
+    // handler itself
+    fn notify_rate_limited(&self, notify: &SplitCommand, message: &HandlerPayload, wait_ms: u128) {
+        let mut command = self.prepare(notify, message);
+        command
+            .env("IMSE_RATE_LIMITED", "1")
+            .env("IMSE_RETRY_AFTER_MS", wait_ms.to_string());
+        if self.dry_run {
+            log_dry_run("notify_on_rate_limit", &command, &self.sanitize_env);
+            return;
+        }
+        tokio::spawn(async move {
+            match command.status().await {
+                Ok(status) => {
+                    tracing::debug!(kind=%"notify_on_rate_limit", rc=status.code().unwrap_or(-1), "complete")
+                }
+                Err(error) => tracing::warn!(kind=%"notify_on_rate_limit", %error, "failure"),
+            }
+        });
+    }
+
+    // Also runs in the background, with a fixed timeout so a stuck notifier
+    // can't accumulate
+    fn notify_error(&self, on_error: &SplitCommand, message: &HandlerPayload, rc: Option<i32>) {
+        let mut command = self.prepare(on_error, message);
+        command
+            .env("IMSE_FAILED_COMMAND", self.prog())
+            .env("IMSE_FAILED_EXIT_CODE", rc.unwrap_or(-1).to_string())
+            .env("IMSE_FAILED_ATTEMPT_COUNT", "1")
+            .kill_on_drop(true);
+        if self.dry_run {
+            log_dry_run("on_error_command", &command, &self.sanitize_env);
+            return;
+        }
+        tokio::spawn(async move {
+            match tokio::time::timeout(Duration::from_secs(30), command.status()).await {
+                Ok(Ok(status)) => {
+                    tracing::debug!(kind=%"on_error_command", rc=status.code().unwrap_or(-1), "complete")
+                }
+                Ok(Err(error)) => tracing::warn!(kind=%"on_error_command", %error, "failure"),
+                Err(_) => tracing::warn!(kind=%"on_error_command", "timeout"),
+            }
+        });
+    }
+
+    fn prepare(&self, command: &SplitCommand, message: &HandlerPayload) -> Command {
+        let mut command = command.as_tokio_command();
+        if self.clear_env {
+            command.env_clear();
+        }
+        if let Some(path) = self.command_path() {
+            command.env("PATH", path);
+        }
         command
-            .env("IMSE_USER", &self.user)
-            .env("IMSE_EVENT", self.event.to_string());
+            .env("IMSE_HANDLER_INDEX", self.index.to_string())
+            .env("IMSE_USER", self.message_user(message))
+            .env("IMSE_EVENT", self.message_event(message));
 
         if let Some(message) = message {
+            if self.event_coalesce {
+                command.env("IMSE_ORIGINAL_EVENT", message.event.to_string());
+            }
+            if let Some(received_at) = message.received_at {
+                let delay = SystemTime::now()
+                    .duration_since(received_at)
+                    .unwrap_or_default();
+                command
+                    .env(
+                        "IMSE_TRIGGERED_AT_ISO8601",
+                        humantime::format_rfc3339_millis(received_at).to_string(),
+                    )
+                    .env("IMSE_DELAY_MS", delay.as_millis().to_string());
+            }
             if let Some(remote) = message.remote_addr {
                 command
                     .env("IMSE_REMOTE_IP", remote.ip().to_string())
                     .env("IMSE_REMOTE_PORT", remote.port().to_string());
             }
+            if self.command_env_json {
+                let mut extra = message.to_json();
+                if let Some(extra) = extra.as_object_mut() {
+                    extra.retain(|field, _| !ENV_FIELDS.contains(&field.as_str()));
+                }
+                command.env("IMSE_MESSAGE_EXTRA_JSON", extra.to_string());
+            }
             command
                 .env("IMSE_UNSEEN", message.unseen.to_string())
                 .env("IMSE_FOLDER", &message.folder)
+                .env(
+                    "IMSE_MAILBOX_ID",
+                    message.mailbox_id.as_deref().unwrap_or(""),
+                )
                 .env("IMSE_FROM", message.from.as_deref().unwrap_or(""))
                 .env("IMSE_SNIPPET", message.snippet.as_deref().unwrap_or(""));
         }
 
+        command
+    }
+
+    fn template_context(&self, message: &HandlerPayload) -> tera::Context {
+        let mut context = tera::Context::new();
+        context.insert("user", self.message_user(message));
+        context.insert("event", &self.message_event(message));
+
+        if let Some(message) = message {
+            if let Some(remote) = message.remote_addr {
+                context.insert("remote_ip", &remote.ip().to_string());
+                context.insert("remote_port", &remote.port());
+            }
+            context.insert("unseen", &message.unseen);
+            context.insert("folder", &message.folder);
+            context.insert("mailbox_id", message.mailbox_id.as_deref().unwrap_or(""));
+            context.insert("from", message.from.as_deref().unwrap_or(""));
+            context.insert("snippet", message.snippet.as_deref().unwrap_or(""));
+        }
+
+        context
+    }
+
+    async fn spawn<F>(
+        &self,
+        kind: &str,
+        mut command: Command,
+        stdin: Option<String>,
+        deadline: Option<Instant>,
+        is_success: F,
+    ) -> (Option<i32>, bool)
+    where
+        F: Fn(i32) -> bool,
+    {
+        if self.dry_run {
+            log_dry_run(kind, &command, &self.sanitize_env);
+            return (Some(0), true);
+        }
+
+        if self.capture_output {
+            command.stdout(Stdio::piped()).stderr(Stdio::piped());
+        }
+
         let start = Instant::now();
-        tracing::info!("spawn");
-        let result = command.status().await;
-        if let Ok(result) = result {
-            tracing::info!(elapsed_ms=%start.elapsed().as_millis(), rc=result.code().unwrap_or(-1), "complete");
-        } else {
-            tracing::error!(status=?result, "failure");
+        let level = self.log_level.map_or(Level::INFO, LoggingLevel::inner);
+        event_at!(level, kind, "spawn");
+        let mut output = None;
+        let result = match command.spawn() {
+            Ok(mut child) => {
+                if self.capture_output {
+                    output = Some((
+                        collect_lines(child.stdout.take()),
+                        collect_lines(child.stderr.take()),
+                    ));
+                }
+                match deadline {
+                    Some(deadline) => {
+                        match timeout_at(deadline, wait_with_stdin(&mut child, stdin)).await {
+                            Ok(result) => result,
+                            Err(_) => self.terminate(&mut child).await,
+                        }
+                    }
+                    None => wait_with_stdin(&mut child, stdin).await,
+                }
+            }
+            Err(error) => Err(error),
+        };
+        let (rc, success) = match &result {
+            Ok(status) => {
+                let rc = status.code().unwrap_or(-1);
+                (Some(rc), is_success(rc))
+            }
+            Err(_) => (None, false),
+        };
+
+        // Output is buffered until exit, and discarded on success if only
+        // failures are interesting
+        if let Some((stdout, stderr)) = output {
+            let (stdout, stderr) = (
+                stdout.await.unwrap_or_default(),
+                stderr.await.unwrap_or_default(),
+            );
+            if !(success && self.output_on_failure_only) {
+                let lines = stdout
+                    .iter()
+                    .map(|line| ("stdout", line))
+                    .chain(stderr.iter().map(|line| ("stderr", line)));
+                for (stream, line) in lines {
+                    if success {
+                        event_at!(level, kind, stream, %line, "output");
+                    } else {
+                        tracing::warn!(kind, stream, %line, "output");
+                    }
+                }
+            }
+        }
+
+        if let Some(rc) = rc {
+            if success {
+                event_at!(level, kind, elapsed_ms=%start.elapsed().as_millis(), rc, success, "complete");
+            } else {
+                tracing::warn!(kind, elapsed_ms=%start.elapsed().as_millis(), rc, success, "complete");
+            }
+        } else if let Err(error) = &result {
+            tracing::error!(kind, %error, error_kind=%error.kind(), os_error=?error.raw_os_error(), "failure");
+            // Enough to reproduce the failure by hand
+            let command = command.as_std();
+            let args: Vec<_> = command.get_args().collect();
+            tracing::debug!(kind, program=?command.get_program(), ?args, "failure");
+        }
+        (rc, success)
+    }
+
+    // Ask the command to exit with SIGTERM, only resorting to SIGKILL if it
+    // hasn't after kill_grace_period
+    async fn terminate(&self, child: &mut Child) -> std::io::Result<ExitStatus> {
+        let pid = child.id();
+
+        #[cfg(unix)]
+        if let Some(pid) = pid {
+            use nix::{
+                sys::signal::{kill, Signal},
+                unistd::Pid,
+            };
+
+            let grace = self
+                .kill_grace_period
+                .map_or(Duration::from_secs(5), Duration::from);
+            tracing::warn!(pid, signal=%"SIGTERM", "command_timeout");
+            match kill(Pid::from_raw(pid as i32), Signal::SIGTERM) {
+                Ok(()) => {
+                    if let Ok(result) = tokio::time::timeout(grace, child.wait()).await {
+                        tracing::info!(pid, exited=%"SIGTERM", "command_timeout");
+                        return result;
+                    }
+                }
+                Err(error) => tracing::warn!(pid, %error, "command_timeout"),
+            }
+        }
+
+        #[cfg(not(unix))]
+        tracing::debug!(
+            ?pid,
+            "command_timeout: no SIGTERM equivalent, terminating immediately"
+        );
+
+        tracing::warn!(?pid, signal=%"SIGKILL", "command_timeout");
+        child.kill().await?;
+        tracing::info!(?pid, exited=%"SIGKILL", "command_timeout");
+        child.wait().await
+    }
+
+    // The notified user, falling back to the configured one for periodic execution
+    fn message_user<'a>(&'a self, message: &'a HandlerPayload) -> &'a str {
+        message.as_deref().map_or_else(
+            || self.user.as_deref().unwrap_or(""),
+            |message| message.user.as_str(),
+        )
+    }
+
+    // The notified event, or MessageNew if coalescing, falling back to the
+    // configured one for periodic execution, or the pattern if there isn't one
+    fn message_event(&self, message: &HandlerPayload) -> String {
+        match (message, self.event, &self.event_pattern) {
+            (Some(_), _, _) if self.event_coalesce => ImseEvent::MessageNew.to_string(),
+            (Some(message), _, _) => message.event.to_string(),
+            (None, _, Some(pattern)) => pattern.as_str().to_string(),
+            (None, event, None) => event.unwrap_or_default().to_string(),
         }
     }
 
+    // A code of -1 represents termination by signal
+    pub fn is_success(&self, rc: i32) -> bool {
+        self.success_codes
+            .as_ref()
+            .map_or(rc == 0, |codes| codes.contains(&rc))
+    }
+
+    pub fn limit_period(&self) -> Duration {
+        self.limit_period
+            .map_or(Duration::from_secs(30), Duration::from)
+    }
+
+    // A limit_burst of 0 disables rate limiting
+    pub fn limit_burst(&self) -> Option<NonZeroU32> {
+        NonZeroU32::new(self.limit_burst.unwrap_or(1))
+    }
+
     pub fn into_sender_handle(self) -> (HandlerSender, tokio::task::JoinHandle<()>) {
         let (tx, rx) = watch::channel::<HandlerPayload>(None);
 
@@ -101,3 +711,81 @@ impl Handler {
         (tx, task)
     }
 }
+
+fn log_dry_run(kind: &str, command: &Command, sanitize: &HashSet<String>) {
+    let command = command.as_std();
+    let args: Vec<_> = command.get_args().collect();
+    let env: Vec<_> = command
+        .get_envs()
+        .filter_map(|(key, value)| {
+            let key = key.to_string_lossy();
+            let value = if sanitize.contains(key.as_ref()) {
+                Cow::from(crate::sanitize::REDACTED)
+            } else {
+                value?.to_string_lossy()
+            };
+            Some(format!("{}={}", key, value))
+        })
+        .collect();
+    tracing::info!(kind, program=?command.get_program(), ?args, ?env, "dry_run");
+}
+
+// Every message in a batch may be waiting on completion, not only the latest
+fn signal_completion(
+    index: usize,
+    message: &HandlerPayload,
+    batch: &[Arc<ImseMessage>],
+    outcome: Completion,
+) {
+    for message in message.iter().chain(batch) {
+        if let Some(completion) = &message.completion {
+            completion.complete(index, outcome);
+        }
+    }
+}
+
+fn write_script(script: &str) -> std::io::Result<tempfile::NamedTempFile> {
+    let mut file = tempfile::Builder::new().prefix("imserious-").tempfile()?;
+    file.write_all(script.as_bytes())?;
+    file.write_all(b"\n")?;
+    file.flush()?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.as_file()
+            .set_permissions(std::fs::Permissions::from_mode(0o700))?;
+    }
+
+    Ok(file)
+}
+
+// Read a stream's lines in the background, so the command never blocks on a
+// full pipe
+fn collect_lines<R>(stream: Option<R>) -> tokio::task::JoinHandle<Vec<String>>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut lines = vec![];
+        if let Some(stream) = stream {
+            let mut reader = BufReader::new(stream).lines();
+            while let Ok(Some(line)) = reader.next_line().await {
+                lines.push(line);
+            }
+        }
+        lines
+    })
+}
+
+async fn wait_with_stdin(child: &mut Child, stdin: Option<String>) -> std::io::Result<ExitStatus> {
+    if let (Some(mut pipe), Some(stdin)) = (child.stdin.take(), stdin) {
+        // Commands are free to exit without reading their input
+        if let Err(error) = pipe.write_all(stdin.as_bytes()).await {
+            if error.kind() != std::io::ErrorKind::BrokenPipe {
+                return Err(error);
+            }
+        }
+    }
+    child.wait().await
+}
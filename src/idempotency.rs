@@ -0,0 +1,55 @@
+use tokio::time::{Duration, Instant};
+
+use std::collections::{HashSet, VecDeque};
+
+// Recently seen request idempotency keys, bounded in both age and number.
+// Keys are never refreshed, so insertion order is also age order
+pub struct IdempotencyKeys {
+    window: Duration,
+    max: usize,
+    seen: HashSet<String>,
+    order: VecDeque<(Instant, String)>,
+}
+
+impl IdempotencyKeys {
+    pub fn new(window: Duration, max: usize) -> Self {
+        Self {
+            window,
+            max,
+            seen: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    // Records the key, returning whether it was already seen within the window
+    pub fn check(&mut self, key: &str) -> bool {
+        let now = Instant::now();
+        while matches!(self.order.front(), Some((seen_at, _)) if now.duration_since(*seen_at) >= self.window)
+        {
+            self.pop_oldest();
+        }
+
+        if self.seen.contains(key) {
+            return true;
+        }
+        while self.order.len() >= self.max {
+            self.pop_oldest();
+        }
+        self.seen.insert(key.to_string());
+        self.order.push_back((now, key.to_string()));
+        false
+    }
+
+    // Forgets a key, so a retry of a failed notification is dispatched again
+    pub fn remove(&mut self, key: &str) {
+        if self.seen.remove(key) {
+            self.order.retain(|(_, seen)| seen != key);
+        }
+    }
+
+    fn pop_oldest(&mut self) {
+        if let Some((_, expired)) = self.order.pop_front() {
+            self.seen.remove(&expired);
+        }
+    }
+}
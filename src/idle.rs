@@ -0,0 +1,210 @@
+use axum_server::accept::Accept;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{sleep, Duration, Instant, Sleep},
+};
+use tower::Service;
+
+use std::{
+    future::{ready, Future, Ready},
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+// Closes connections which see no reads or writes for the configured duration
+// while no request is being handled, or which can't write for write_timeout,
+// as when the client stops reading.  Connections are closed without a response,
+// as idle keep-alive connections have no request to answer
+#[derive(Clone, Copy, Debug)]
+pub struct IdleTimeoutAcceptor {
+    timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+}
+
+impl IdleTimeoutAcceptor {
+    pub fn new(timeout: Option<Duration>, write_timeout: Option<Duration>) -> Self {
+        Self {
+            timeout,
+            write_timeout,
+        }
+    }
+}
+
+impl<I, S> Accept<I, S> for IdleTimeoutAcceptor
+where
+    I: AsyncRead + AsyncWrite + Unpin,
+{
+    type Stream = IdleTimeout<I>;
+    type Service = TrackRequests<S>;
+    type Future = Ready<io::Result<(Self::Stream, Self::Service)>>;
+
+    fn accept(&self, stream: I, service: S) -> Self::Future {
+        let timeout = self
+            .timeout
+            .map(|timeout| (timeout, Box::pin(sleep(timeout))));
+        let active = Arc::new(AtomicUsize::new(0));
+        ready(Ok((
+            IdleTimeout {
+                inner: stream,
+                timeout,
+                write_timeout: self.write_timeout,
+                write_stalled: None,
+                active: Arc::clone(&active),
+            },
+            TrackRequests {
+                inner: service,
+                active,
+            },
+        )))
+    }
+}
+
+pub struct IdleTimeout<I> {
+    inner: I,
+    timeout: Option<(Duration, Pin<Box<Sleep>>)>,
+    write_timeout: Option<Duration>,
+    // Started when a write or flush can't proceed, cleared once one does
+    write_stalled: Option<Pin<Box<Sleep>>>,
+    // Requests on this connection awaiting a response
+    active: Arc<AtomicUsize>,
+}
+
+impl<I> IdleTimeout<I> {
+    fn poll_activity<T>(
+        &mut self,
+        cx: &mut Context<'_>,
+        poll: Poll<io::Result<T>>,
+    ) -> Poll<io::Result<T>> {
+        if let Some((timeout, sleep)) = &mut self.timeout {
+            if poll.is_ready() {
+                sleep.as_mut().reset(Instant::now() + *timeout);
+            } else if sleep.as_mut().poll(cx).is_ready() {
+                if self.active.load(Ordering::Relaxed) == 0 {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "connection idle timeout",
+                    )));
+                }
+                // Waiting on a response isn't idle, so check again later
+                sleep.as_mut().reset(Instant::now() + *timeout);
+                let _ = sleep.as_mut().poll(cx);
+            }
+        }
+        poll
+    }
+
+    fn poll_write_stall<T>(
+        &mut self,
+        cx: &mut Context<'_>,
+        poll: Poll<io::Result<T>>,
+    ) -> Poll<io::Result<T>> {
+        let write_timeout = match self.write_timeout {
+            Some(write_timeout) => write_timeout,
+            None => return poll,
+        };
+        if poll.is_ready() {
+            self.write_stalled = None;
+        } else if self
+            .write_stalled
+            .get_or_insert_with(|| Box::pin(sleep(write_timeout)))
+            .as_mut()
+            .poll(cx)
+            .is_ready()
+        {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "connection write timeout",
+            )));
+        }
+        poll
+    }
+}
+
+impl<I: AsyncRead + Unpin> AsyncRead for IdleTimeout<I> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        this.poll_activity(cx, poll)
+    }
+}
+
+impl<I: AsyncWrite + Unpin> AsyncWrite for IdleTimeout<I> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        let poll = this.poll_write_stall(cx, poll);
+        this.poll_activity(cx, poll)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_flush(cx);
+        this.poll_write_stall(cx, poll)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+// Counts the connection's requests which are awaiting a response
+#[derive(Clone)]
+pub struct TrackRequests<S> {
+    inner: S,
+    active: Arc<AtomicUsize>,
+}
+
+impl<S, R> Service<R> for TrackRequests<S>
+where
+    S: Service<R>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Tracked<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: R) -> Self::Future {
+        self.active.fetch_add(1, Ordering::Relaxed);
+        Tracked {
+            inner: Box::pin(self.inner.call(request)),
+            _active: Active(Arc::clone(&self.active)),
+        }
+    }
+}
+
+pub struct Tracked<F> {
+    inner: Pin<Box<F>>,
+    _active: Active,
+}
+
+impl<F: Future> Future for Tracked<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.inner.as_mut().poll(cx)
+    }
+}
+
+struct Active(Arc<AtomicUsize>);
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
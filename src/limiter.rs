@@ -0,0 +1,145 @@
+use governor::{
+    clock::MonotonicClock,
+    state::{InMemoryState, NotKeyed},
+    Quota, RateLimiter,
+};
+use tokio::time::{Duration, Instant};
+
+use std::{
+    collections::{HashMap, VecDeque},
+    num::NonZeroU32,
+};
+
+use crate::config::RateLimitMode;
+
+pub enum Limiter {
+    TokenBucket(RateLimiter<NotKeyed, InMemoryState, MonotonicClock>),
+    FixedWindow {
+        period: Duration,
+        burst: u32,
+        window_start: Option<Instant>,
+        count: u32,
+    },
+    SlidingWindow {
+        period: Duration,
+        burst: u32,
+        recent: VecDeque<Instant>,
+    },
+}
+
+impl Limiter {
+    pub fn new(mode: RateLimitMode, period: Duration, burst: NonZeroU32) -> Self {
+        match mode {
+            RateLimitMode::TokenBucket => {
+                let quota = Quota::with_period(period)
+                    .expect("Non-zero Duration")
+                    .allow_burst(burst);
+                Self::TokenBucket(RateLimiter::direct_with_clock(quota, &MonotonicClock))
+            }
+            RateLimitMode::FixedWindow => Self::FixedWindow {
+                period,
+                burst: burst.get(),
+                window_start: None,
+                count: 0,
+            },
+            RateLimitMode::SlidingWindow => Self::SlidingWindow {
+                period,
+                burst: burst.get(),
+                recent: VecDeque::with_capacity(burst.get() as usize),
+            },
+        }
+    }
+
+    // Returns the earliest time another execution may be allowed
+    pub fn check(&mut self) -> Result<(), Instant> {
+        let now = Instant::now();
+        match self {
+            Self::TokenBucket(limiter) => limiter
+                .check()
+                .map_err(|not_until| not_until.earliest_possible().into()),
+            Self::FixedWindow {
+                period,
+                burst,
+                window_start,
+                count,
+            } => {
+                let start = match window_start {
+                    Some(start) if now < *start + *period => *start,
+                    _ => {
+                        *count = 0;
+                        *window_start.insert(now)
+                    }
+                };
+                if *count < *burst {
+                    *count += 1;
+                    Ok(())
+                } else {
+                    Err(start + *period)
+                }
+            }
+            Self::SlidingWindow {
+                period,
+                burst,
+                recent,
+            } => {
+                while matches!(recent.front(), Some(&oldest) if oldest + *period <= now) {
+                    recent.pop_front();
+                }
+                if recent.len() < *burst as usize {
+                    recent.push_back(now);
+                    Ok(())
+                } else {
+                    Err(recent[0] + *period)
+                }
+            }
+        }
+    }
+}
+
+// Independent limiters per key, created on first use.  Limiters idle long
+// enough to have fully recovered are evicted, as a fresh one is equivalent
+pub struct KeyedLimiter {
+    mode: RateLimitMode,
+    period: Duration,
+    burst: NonZeroU32,
+    idle: Duration,
+    limiters: HashMap<String, (Limiter, Instant)>,
+}
+
+impl std::fmt::Debug for KeyedLimiter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeyedLimiter")
+            .field("mode", &self.mode)
+            .field("period", &self.period)
+            .field("burst", &self.burst)
+            .field("keys", &self.limiters.len())
+            .finish()
+    }
+}
+
+impl KeyedLimiter {
+    pub fn new(mode: RateLimitMode, period: Duration, burst: NonZeroU32) -> Self {
+        Self {
+            mode,
+            period,
+            burst,
+            idle: std::cmp::max(period * 2, period * burst.get()),
+            limiters: HashMap::new(),
+        }
+    }
+
+    pub fn check(&mut self, key: &str) -> Result<(), Instant> {
+        let now = Instant::now();
+        let idle = self.idle;
+        self.limiters
+            .retain(|_, (_, last_used)| now.duration_since(*last_used) < idle);
+
+        let (mode, period, burst) = (self.mode, self.period, self.burst);
+        let (limiter, last_used) = self
+            .limiters
+            .entry(key.to_string())
+            .or_insert_with(|| (Limiter::new(mode, period, burst), now));
+        *last_used = now;
+        limiter.check()
+    }
+}
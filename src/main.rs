@@ -1,29 +1,86 @@
-use anyhow::{Context, Result};
+use anyhow::{bail, ensure, Context, Result};
 use axum::{
+    body::{Body, Bytes},
     error_handling::HandleErrorLayer,
-    extract::{ConnectInfo, DefaultBodyLimit, State},
-    http::{Request, StatusCode},
+    extract::{ConnectInfo, DefaultBodyLimit, FromRequest, State},
+    http::{
+        header::{HeaderValue, CONTENT_TYPE, RETRY_AFTER, WWW_AUTHENTICATE},
+        HeaderMap, Method, Request, StatusCode,
+    },
     middleware::{self, Next},
-    response::IntoResponse,
-    routing::put,
+    response::{IntoResponse, Response},
+    routing::{get, put},
     Json, Router,
 };
-use axum_server::{tls_rustls::RustlsConfig, Handle};
+use axum_server::{
+    tls_rustls::{RustlsAcceptor, RustlsConfig},
+    Handle, HttpConfig,
+};
+use futures::stream::{FuturesUnordered, StreamExt};
+use governor::{DefaultKeyedRateLimiter, Quota, RateLimiter};
 use gumdrop::Options;
-use tokio::{signal, time::Duration};
+use rand::Rng;
+use tokio::{
+    signal,
+    time::{Duration, Instant},
+};
 use tower::{BoxError, ServiceBuilder};
-use tower_http::{trace::TraceLayer, validate_request::ValidateRequestHeaderLayer};
-use tracing_subscriber::prelude::*;
+use tower_http::{
+    auth::AsyncRequireAuthorizationLayer,
+    classify::ServerErrorsFailureClass,
+    set_header::SetResponseHeaderLayer,
+    trace::{
+        DefaultMakeSpan, DefaultOnFailure, DefaultOnRequest, DefaultOnResponse, MakeSpan,
+        OnFailure, OnRequest, OnResponse, TraceLayer,
+    },
+};
+use tracing::{field::display, Span};
+use tracing_subscriber::{
+    fmt::{format::Writer, time::FormatTime},
+    prelude::*,
+};
 
-use std::{borrow::Cow, net::SocketAddr, path::PathBuf, sync::Arc};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    num::NonZeroUsize,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::SystemTime,
+};
 
+mod allow;
+mod auth;
 mod config;
+mod diagnose;
+mod example_config;
 mod handler;
+mod idempotency;
+mod idle;
+mod limiter;
 mod message;
+mod sanitize;
+mod state;
+mod tls;
+mod userlist;
+#[cfg(feature = "wasm")]
+mod wasm;
 use crate::{
-    config::{Config, Handler, LoggingFormat},
+    allow::AllowList,
+    auth::BasicAuth,
+    config::{Config, EventField, Handler, HttpVersion, LoggingFormat, LoggingLevel},
     handler::HandlerSender,
-    message::ImseMessage,
+    idempotency::IdempotencyKeys,
+    idle::IdleTimeoutAcceptor,
+    limiter::KeyedLimiter,
+    message::{Completion, Completions, ImseEvent, ImseMessage},
+    sanitize::{SanitizeFields, SanitizeFormat, SanitizeJsonFields},
+    tls::HandshakeLogAcceptor,
 };
 
 const DEFAULT_CONFIG: &str = "/usr/local/etc/imserious.toml";
@@ -34,18 +91,92 @@ struct Args {
     help: bool,
     #[options(help = "print program version")]
     version: bool,
+    #[options(no_short, help = "print program version as JSON")]
+    version_json: bool,
     #[options(help = "test configuration")]
     test: bool,
+    #[options(no_short, help = "show handler summary when testing configuration")]
+    verbose: bool,
+    #[options(no_short, help = "treat configuration warnings as errors")]
+    strict: bool,
     #[options(help = "path to configuration")]
     config: Option<PathBuf>,
+    #[options(no_short, help = "print an example configuration")]
+    print_example: bool,
+    #[options(no_short, help = "check the configuration against the running system")]
+    diagnose: bool,
+    #[options(no_short, meta = "TAG", help = "disable handlers with this tag")]
+    disable_tag: Vec<String>,
+    #[options(no_short, meta = "TAG", help = "disable handlers without this tag")]
+    enable_only_tag: Vec<String>,
+}
+
+struct AppState {
+    handlers: Vec<(Handler, HandlerSender)>,
+    allow: Arc<AllowList>,
+    sync_response: bool,
+    strict_content_type: bool,
+    user_normalize: bool,
+    read_timeout: Option<Duration>,
+    sync_response_timeout: Duration,
+    idempotency_keys: Mutex<IdempotencyKeys>,
+    filter_events: Vec<ImseEvent>,
+    event_fields: Vec<EventField>,
+    max_handlers_per_message: Option<NonZeroUsize>,
+}
+
+// Tracks requests in flight through the load shedding queue
+struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    // Counted until the response is ready, rather than when the request is consumed
+    async fn until<F: Future>(self, response: F) -> F::Output {
+        response.await
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Log timestamps in a custom strftime format, checked by Config::validate
+#[derive(Clone)]
+enum LogTimer {
+    Default(tracing_subscriber::fmt::time::SystemTime),
+    Format(String),
+}
+
+impl LogTimer {
+    fn new(format: &Option<String>) -> Self {
+        format.clone().map_or_else(
+            || Self::Default(tracing_subscriber::fmt::time::SystemTime),
+            Self::Format,
+        )
+    }
+}
+
+impl FormatTime for LogTimer {
+    fn format_time(&self, w: &mut Writer<'_>) -> std::fmt::Result {
+        match self {
+            Self::Default(timer) => timer.format_time(w),
+            Self::Format(format) => write!(w, "{}", chrono::Local::now().format(format)),
+        }
+    }
 }
 
 macro_rules! log_format {
-    ($config:ident => {$($kind:pat => $format:expr,)*}) => {
-        match $config.log.format {
+    ($log_format:expr, $timestamp:expr, $sanitize:expr => {$($kind:pat => $format:expr,)*}) => {
+        match $log_format {
             $(
-                $kind if $config.log.timestamp => $format.boxed(),
-                $kind => $format.without_time().boxed(),
+                $kind if $timestamp => $format
+                    .map_event_format(|format| SanitizeFormat::new(format, Arc::clone(&$sanitize)))
+                    .boxed(),
+                $kind => $format
+                    .without_time()
+                    .map_event_format(|format| SanitizeFormat::new(format, Arc::clone(&$sanitize)))
+                    .boxed(),
             )*
         }
     };
@@ -60,40 +191,191 @@ async fn main() -> Result<()> {
         return Ok(());
     }
 
+    if args.version_json {
+        println!(
+            "{}",
+            serde_json::json!({
+                "name": env!("CARGO_PKG_NAME"),
+                "version": env!("CARGO_PKG_VERSION"),
+                "build_date": env!("IMSERIOUS_BUILD_DATE"),
+                "rustc_version": env!("IMSERIOUS_RUSTC_VERSION"),
+                "git_sha": env!("IMSERIOUS_GIT_SHA"),
+            })
+        );
+        return Ok(());
+    }
+
+    if args.print_example {
+        print!("{}", example_config::EXAMPLE_CONFIG);
+        return Ok(());
+    }
+
     let path = args.config.unwrap_or_else(|| DEFAULT_CONFIG.into());
 
-    let config = Config::from_path(&path)
+    let mut config = Config::from_path(&path)
         .with_context(|| format!("Failed to load configuration from {}", path.display()))?;
 
+    for handler in &mut config.handler {
+        if handler.has_any_tag(&args.disable_tag)
+            || (!args.enable_only_tag.is_empty() && !handler.has_any_tag(&args.enable_only_tag))
+        {
+            handler.disable = true;
+        }
+    }
+
+    let mut errors = config.validate();
+    let mut warnings = config.warnings();
+    if args.strict {
+        errors.append(&mut warnings);
+    }
+
+    if args.diagnose {
+        return diagnose::diagnose(config, errors).await;
+    }
+
     if args.test {
+        if args.verbose {
+            for handler in &config.handler {
+                eprintln!(
+                    "{}: event={} user={} limit={} delay={}{}",
+                    handler.label(),
+                    handler.event_pattern.as_ref().map_or_else(
+                        || handler.event.unwrap_or_default().to_string(),
+                        |pattern| format!("{} (glob)", pattern.as_str())
+                    ),
+                    handler.user_or_pattern(),
+                    match (&handler.rate_limit_group, handler.limit_burst()) {
+                        (Some(group), _) => format!("group {}", group),
+                        (None, Some(burst)) => format!(
+                            "{}/{}",
+                            burst,
+                            humantime::format_duration(handler.limit_period())
+                        ),
+                        (None, None) => "none".to_string(),
+                    },
+                    handler.delay.map_or_else(
+                        || "none".to_string(),
+                        |delay| humantime::format_duration(delay.into_std()).to_string()
+                    ),
+                    if handler.dry_run { " dry_run=true" } else { "" }
+                );
+            }
+        }
+
+        if let (true, Some(tls)) = (args.verbose, &config.tls) {
+            eprintln!(
+                "tls: cert={} key={}",
+                tls.cert.as_deref().unwrap_or("(inline)"),
+                tls.key.as_deref().unwrap_or("(inline, masked)")
+            );
+        }
+
+        for warning in &warnings {
+            eprintln!("Config warning: {}", warning);
+        }
+
+        for error in &errors {
+            eprintln!("Config error: {}", error);
+        }
+
+        if !errors.is_empty() {
+            bail!(
+                "{} error(s) in configuration {}",
+                errors.len(),
+                path.display()
+            );
+        }
+
         eprintln!("Config OK: {}", path.display());
         return Ok(());
     }
 
-    let filter = tracing_subscriber::filter::EnvFilter::builder()
+    ensure!(
+        errors.is_empty(),
+        "Invalid configuration {}: {}",
+        path.display(),
+        errors.join(", ")
+    );
+
+    let mut filter = tracing_subscriber::filter::EnvFilter::builder()
         .with_default_directive(config.log.max_level.inner().into())
         .with_env_var("IMSERIOUS_LOG")
         .from_env_lossy();
 
+    // IMSERIOUS_LOG overrides these too, directives are checked by Config::validate
+    if std::env::var_os("IMSERIOUS_LOG").is_none() {
+        for directive in config.log.target_directives() {
+            filter = filter.add_directive(directive.parse()?);
+        }
+    }
+
+    let timer = LogTimer::new(&config.log.timestamp_format);
+    let sanitize = Arc::new(config.log.sanitized_fields());
     let format = tracing_subscriber::fmt::layer()
+        .with_timer(timer.clone())
         .with_target(config.log.target)
         .with_level(config.log.level)
         .with_ansi(config.log.ansi);
 
+    let file_layer = match &config.log.file {
+        Some(path) => {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("Failed to open log file {}", path.display()))?;
+
+            let format = tracing_subscriber::fmt::layer()
+                .with_writer(Arc::new(file))
+                .with_timer(timer)
+                .with_target(config.log.target)
+                .with_level(config.log.level)
+                .with_ansi(false);
+
+            Some(log_format! {
+                config.log.file_format.unwrap_or(config.log.format), config.log.timestamp, sanitize => {
+                    LoggingFormat::Full => format
+                        .map_fmt_fields(|fields| SanitizeFields::new(fields, Arc::clone(&sanitize))),
+                    LoggingFormat::Compact => format
+                        .compact()
+                        .map_fmt_fields(|fields| SanitizeFields::new(fields, Arc::clone(&sanitize))),
+                    LoggingFormat::Pretty => format
+                        .pretty()
+                        .map_fmt_fields(|fields| SanitizeFields::new(fields, Arc::clone(&sanitize))),
+                    LoggingFormat::Json => format
+                        .json()
+                        .map_fmt_fields(|_| SanitizeJsonFields::new(Arc::clone(&sanitize))),
+                }
+            })
+        }
+        None => None,
+    };
+
     tracing_subscriber::registry()
         .with(filter)
         .with(log_format! {
-            config => {
-                LoggingFormat::Full => format,
-                LoggingFormat::Compact => format.compact(),
-                LoggingFormat::Pretty => format.pretty(),
-                LoggingFormat::Json => format.json(),
+            config.log.format, config.log.timestamp, sanitize => {
+                LoggingFormat::Full => format
+                    .map_fmt_fields(|fields| SanitizeFields::new(fields, Arc::clone(&sanitize))),
+                LoggingFormat::Compact => format
+                    .compact()
+                    .map_fmt_fields(|fields| SanitizeFields::new(fields, Arc::clone(&sanitize))),
+                LoggingFormat::Pretty => format
+                    .pretty()
+                    .map_fmt_fields(|fields| SanitizeFields::new(fields, Arc::clone(&sanitize))),
+                LoggingFormat::Json => format
+                    .json()
+                    .map_fmt_fields(|_| SanitizeJsonFields::new(Arc::clone(&sanitize))),
             }
         })
+        .with(file_layer)
         .init();
 
     tracing::info!(name=%env!("CARGO_PKG_NAME"), version=%env!("CARGO_PKG_VERSION"), config=%path.display(), "start");
-    let res = run(config).await;
+    for warning in &warnings {
+        tracing::warn!(%warning, "config");
+    }
+    let res = run(config, None).await;
     if let Err(ref error) = res {
         tracing::error!(%error);
         for error_cause in error.chain().skip(1) {
@@ -104,42 +386,206 @@ async fn main() -> Result<()> {
     res
 }
 
-async fn run(config: Config) -> Result<()> {
+// Serves on the given listener, or binds listen itself
+async fn run(config: Config, listener: Option<std::net::TcpListener>) -> Result<()> {
+    if config.listen_addr().ip().is_unspecified() && config.allow.is_none() && config.auth.is_none()
+    {
+        tracing::warn!(
+            listen=%config.listen_addr(),
+            "Listening on all interfaces without authentication or IP restriction - ensure firewall rules are in place"
+        );
+    }
+
+    // Limiters are only created once their periods have been validated
+    let groups: HashMap<_, _> = config
+        .rate_limit_group
+        .iter()
+        .map(|group| {
+            let limiter = KeyedLimiter::new(
+                group.rate_limit_mode.unwrap_or_default(),
+                group.limit_period(),
+                group.limit_burst(),
+            );
+            (group.name.as_str(), Arc::new(Mutex::new(limiter)))
+        })
+        .collect();
+
     let mut handlers = vec![];
     let mut tasks = vec![];
-    for handler in config.handler {
+    for mut handler in config.handler {
+        if handler.disable {
+            tracing::info!(handler=%handler.label(), "disabled_handler");
+            continue;
+        }
         tracing::debug!(?handler, "register_handler");
+        if let Some(group) = &handler.rate_limit_group {
+            handler.shared_limiter = groups.get(group.as_str()).cloned();
+        }
+        if let Some(list) = &handler.user_list {
+            tokio::spawn(list.clone().refresh());
+        }
         let (tx, task) = handler.clone().into_sender_handle();
         tasks.push(task);
         handlers.push((handler, tx));
     }
 
-    let allow = Arc::new(config.allow);
+    let allow = Arc::new(AllowList::new(config.allow).await);
+    if allow.has_hostnames() {
+        tokio::spawn(
+            allow
+                .clone()
+                .refresh(config.allow_dns_ttl.map(Duration::from)),
+        );
+    }
+    let ip_limiter = config.max_requests_per_ip_per_second.map(|limit| {
+        let limiter = Arc::new(RateLimiter::keyed(Quota::per_second(limit)));
+        tokio::spawn(evict_ip_limits(limiter.clone()));
+        limiter
+    });
+    // Finer timeouts never extend the overall request timeout
+    let request_timeout = config
+        .timeout
+        .map_or(Duration::from_secs(5), Duration::from);
+    let state = Arc::new(AppState {
+        handlers,
+        allow: allow.clone(),
+        sync_response: config.sync_response,
+        strict_content_type: config.strict_content_type.unwrap_or(true),
+        user_normalize: config.user_normalize,
+        read_timeout: config
+            .read_timeout
+            .map(|limit| Duration::from(limit).min(request_timeout)),
+        // Leave time to respond before the request itself times out
+        sync_response_timeout: config
+            .sync_response_timeout
+            .map_or(Duration::MAX, Duration::from)
+            .min(request_timeout - request_timeout / 10),
+        idempotency_keys: Mutex::new(IdempotencyKeys::new(
+            config
+                .idempotency_window
+                .map_or(Duration::from_secs(60), Duration::from),
+            config
+                .max_idempotency_keys
+                .map_or(10_000, NonZeroUsize::get),
+        )),
+        filter_events: config.log.filter_events.unwrap_or_default(),
+        max_handlers_per_message: config.max_handlers_per_message,
+        event_fields: config
+            .log
+            .event_fields
+            .unwrap_or_else(|| EventField::DEFAULT.to_vec()),
+    });
+
+    let max_connections = config.max_connections();
+    let load_shed_queue = config.load_shed_queue.map(|queue| {
+        let queue = queue.get();
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        ServiceBuilder::new()
+            .map_future(move |response| {
+                let queued =
+                    (in_flight.fetch_add(1, Ordering::Relaxed) + 1).saturating_sub(max_connections);
+                if queued * 5 > queue * 4 {
+                    tracing::warn!(queued, queue, "load_shed_queue");
+                }
+                InFlight(in_flight.clone()).until(response)
+            })
+            .buffer(queue)
+    });
+
+    let auth = match config.auth {
+        Some(auth) => {
+            let basic = BasicAuth::from_config(&auth)
+                .await
+                .context("loading auth")?;
+            if auth.htpasswd_file.is_some() {
+                tokio::spawn(basic.clone().reload_on_hangup());
+            }
+            Some((basic, auth.realm))
+        }
+        None => None,
+    };
 
+    let endpoint = config.endpoint.as_deref().unwrap_or("/notify");
+    let health_checks = config
+        .log
+        .suppress_health_checks
+        .then(|| Arc::<[String]>::from(config.log.health_check_paths()));
+    let request_log = config.log.request_log.unwrap_or(true);
+    let (mut make_span, mut on_request, mut on_response) = (
+        DefaultMakeSpan::new(),
+        DefaultOnRequest::new(),
+        DefaultOnResponse::new(),
+    );
+    if let Some(level) = config.log.request_log_level.map(LoggingLevel::inner) {
+        make_span = make_span.level(level);
+        on_request = on_request.level(level);
+        on_response = on_response.level(level);
+    }
     let app = Router::new()
-        .route(config.endpoint.as_deref().unwrap_or("/notify"), put(notify))
+        .route(endpoint, put(notify))
+        .route("/config/allow", get(config_allow))
         .layer(
             ServiceBuilder::new()
                 .layer(HandleErrorLayer::new(handle_error))
                 .load_shed()
-                .concurrency_limit(config.max_connections.map_or(8, |x| x.get()) as usize)
-                .timeout(
-                    config
-                        .timeout
-                        .map_or(Duration::from_secs(5), Duration::from),
-                )
-                .layer(TraceLayer::new_for_http())
-                .option_layer(
-                    config
-                        .auth
-                        .map(|auth| ValidateRequestHeaderLayer::basic(&auth.user, &auth.pass)),
+                .option_layer(load_shed_queue)
+                .concurrency_limit(max_connections)
+                .timeout(request_timeout)
+                .layer(
+                    TraceLayer::new_for_http()
+                        .make_span_with(move |request: &Request<Body>| {
+                            if !request_log || is_health_check(request, health_checks.as_deref()) {
+                                Span::none()
+                            } else {
+                                make_span.clone().make_span(request)
+                            }
+                        })
+                        // Suppressed requests have a disabled span
+                        .on_request(move |request: &Request<Body>, span: &Span| {
+                            if !span.is_none() {
+                                on_request.clone().on_request(request, span);
+                            }
+                        })
+                        .on_response(move |response: &Response, latency: Duration, span: &Span| {
+                            if !span.is_none() {
+                                on_response.clone().on_response(response, latency, span);
+                            }
+                        })
+                        .on_failure(
+                            |failure: ServerErrorsFailureClass, latency: Duration, span: &Span| {
+                                if !span.is_none() {
+                                    DefaultOnFailure::new().on_failure(failure, latency, span);
+                                }
+                            },
+                        ),
                 )
-                .layer(DefaultBodyLimit::max(1024))
+                .option_layer(auth.map(|(auth, realm)| {
+                    // auth.realm is validated, but the endpoint fallback is not
+                    let realm = realm.as_deref().unwrap_or(endpoint);
+                    let challenge = HeaderValue::from_str(&format!("Basic realm=\"{}\"", realm))
+                        .unwrap_or_else(|_| HeaderValue::from_static("Basic"));
+                    ServiceBuilder::new()
+                        .layer(SetResponseHeaderLayer::if_not_present(
+                            WWW_AUTHENTICATE,
+                            move |response: &Response| {
+                                (response.status() == StatusCode::UNAUTHORIZED)
+                                    .then(|| challenge.clone())
+                            },
+                        ))
+                        .layer(AsyncRequireAuthorizationLayer::new(
+                            move |request: Request<Body>| {
+                                let auth = auth.clone();
+                                async move { auth.validate(request).await }
+                            },
+                        ))
+                        .into_inner()
+                }))
+                .layer(DefaultBodyLimit::max(config.max_body_bytes.unwrap_or(1024)))
                 .into_inner(),
         )
-        .with_state(Arc::new(handlers))
+        .with_state(state)
         .route_layer(middleware::from_fn(move |req, next| {
-            ip_restriction(req, next, allow.clone())
+            ip_restriction(req, next, allow.clone(), ip_limiter.clone())
         }));
 
     let handle = Handle::new();
@@ -149,32 +595,64 @@ async fn run(config: Config) -> Result<()> {
         h.shutdown();
     });
 
-    let addr = config
-        .listen
-        .unwrap_or_else(|| SocketAddr::from(([127, 0, 0, 1], 12525)));
+    let addr = config.listen_addr();
+
+    let mut http_config = HttpConfig::new();
+    if let Some(max_header_bytes) = config.max_header_bytes {
+        http_config.http1_max_buf_size(max_header_bytes);
+    }
+    match config.http_version {
+        HttpVersion::Http1 => {
+            http_config.http1_only(true);
+        }
+        HttpVersion::Http2 => {
+            http_config.http2_only(true);
+        }
+        HttpVersion::Auto => (),
+    }
+    let http_config = http_config.build();
+    let acceptor = IdleTimeoutAcceptor::new(
+        config.connection_timeout.map(Duration::from),
+        config
+            .write_timeout
+            .map(|limit| Duration::from(limit).min(request_timeout)),
+    );
+
+    let server = match (listener, &config.bind_device) {
+        (Some(listener), _) => axum_server::from_tcp(listener),
+        (None, Some(device)) => axum_server::from_tcp(
+            bind_device(addr, device)
+                .with_context(|| format!("binding {} on device {}", addr, device))?,
+        ),
+        (None, None) => axum_server::bind(addr),
+    };
 
-    tracing::info!(%addr, tls=config.tls.is_some(), "listen");
+    tracing::info!(%addr, device=?config.bind_device, tls=config.tls.is_some(), "listen");
 
     if let Some(tls) = config.tls {
-        let tls_config = RustlsConfig::from_pem_file(&tls.cert, &tls.key)
-            .await
-            .with_context(|| {
-                format!(
-                    "creating TLS configuration, cert={} key={}",
-                    tls.cert, tls.key
-                )
-            })?;
+        let tls_config = RustlsConfig::from_config(
+            tls::server_config(&tls, config.http_version)
+                .await
+                .context("creating TLS configuration")?,
+        );
 
-        if tls.periodic_reload.is_some() {
-            tokio::spawn(tls_reload(tls_config.clone(), tls));
+        // SIGUSR1 reloads on demand, on platforms which have it
+        if cfg!(unix) || tls.periodic_reload.is_some() || tls.session_ticket_rotation.is_some() {
+            tokio::spawn(tls_reload(tls_config.clone(), tls, config.http_version));
         }
 
-        axum_server::bind_rustls(addr, tls_config)
+        server
+            .acceptor(HandshakeLogAcceptor::new(
+                RustlsAcceptor::new(tls_config).acceptor(acceptor),
+            ))
+            .http_config(http_config)
             .handle(handle)
             .serve(app.into_make_service_with_connect_info::<SocketAddr>())
             .await?;
     } else {
-        axum_server::bind(addr)
+        server
+            .acceptor(acceptor)
+            .http_config(http_config)
             .handle(handle)
             .serve(app.into_make_service_with_connect_info::<SocketAddr>())
             .await?;
@@ -187,70 +665,400 @@ async fn run(config: Config) -> Result<()> {
     Ok(())
 }
 
-async fn tls_reload(config: RustlsConfig, tls: crate::config::TlsConfig) {
-    let period = tls
-        .periodic_reload
-        .expect("Periodic reload should be specified")
-        .into_std();
-    let mut delay = period;
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn bind_device(addr: SocketAddr, device: &str) -> Result<std::net::TcpListener> {
+    use socket2::{Domain, Socket, Type};
+
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    socket.set_reuse_address(true)?;
+    socket.bind_device(Some(device.as_bytes()))?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn bind_device(addr: SocketAddr, device: &str) -> Result<std::net::TcpListener> {
+    tracing::warn!(%device, "bind_device is not supported on this platform, ignoring");
+    let listener = std::net::TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+async fn tls_reload(
+    config: RustlsConfig,
+    tls: crate::config::TlsConfig,
+    http_version: HttpVersion,
+) {
+    // Rebuilding the configuration also generates fresh session ticket keys
+    let period = [tls.periodic_reload, tls.session_ticket_rotation]
+        .into_iter()
+        .flatten()
+        .map(|period| period.into_std())
+        .min();
+
+    #[cfg(unix)]
+    let mut user1 = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined1())
+        .map_err(|error| tracing::warn!(%error, "tls"))
+        .ok();
+
+    let mut delay = period.map(jitter);
     let mut fails = 0;
     loop {
-        tokio::time::sleep(delay).await;
-        let res = config.reload_from_pem_file(&tls.cert, &tls.key).await;
+        let sleep = async {
+            match delay {
+                Some(delay) => tokio::time::sleep(delay).await,
+                None => std::future::pending().await,
+            }
+        };
+        let signalled = async {
+            #[cfg(unix)]
+            if let Some(user1) = &mut user1 {
+                return user1.recv().await;
+            }
+            std::future::pending::<Option<()>>().await
+        };
+        let trigger = tokio::select! {
+            _ = sleep => "periodic",
+            Some(()) = signalled => "SIGUSR1",
+        };
+
+        let res = tls::server_config(&tls, http_version)
+            .await
+            .map(|server_config| config.reload_from_config(server_config));
         match res {
             Ok(_) => {
                 fails = 0;
-                delay = period;
-                tracing::info!(reload=%"success", next=?delay, "tls");
+                delay = period.map(jitter);
+                tracing::info!(reload=%"success", trigger, next=?delay, "tls");
             }
             Err(e) => {
                 fails += 1;
-                delay = Duration::from_secs(60 * std::cmp::min(15, fails));
-                tracing::error!(reload=%"error", retry=?delay, error=%e, "tls");
+                delay = Some(jitter(Duration::from_secs(60 * std::cmp::min(15, fails))));
+                tracing::error!(reload=%"error", trigger, retry=?delay, error=%e, "tls");
             }
         }
     }
 }
 
+// Spread reloads by ±20% so a fleet restarted together doesn't reload in lockstep
+fn jitter(delay: Duration) -> Duration {
+    delay.mul_f64(rand::thread_rng().gen_range(0.8..1.2))
+}
+
+// Only with suppress_health_checks, which gives the paths to match
+fn is_health_check<B>(request: &Request<B>, paths: Option<&[String]>) -> bool {
+    request.method() == Method::GET
+        && paths.is_some_and(|paths| paths.iter().any(|path| path == request.uri().path()))
+}
+
 async fn ip_restriction<B>(
     req: Request<B>,
     next: Next<B>,
-    allowed_ranges: Arc<Vec<ipnet::IpNet>>,
+    allow: Arc<AllowList>,
+    ip_limiter: Option<Arc<DefaultKeyedRateLimiter<IpAddr>>>,
 ) -> impl IntoResponse {
     let ConnectInfo(remote_addr): &ConnectInfo<SocketAddr> =
         req.extensions().get().expect("ConnectInfo<SocketAddr>");
-    if allowed_ranges.is_empty()
-        || allowed_ranges
-            .iter()
-            .any(|range| range.contains(&remote_addr.ip()))
-    {
-        Ok(next.run(req).await)
-    } else {
+    if !allow.permits(&remote_addr.ip()) {
         tracing::warn!(%remote_addr, method=%req.method(), uri=%req.uri(), "reject");
-        Err(StatusCode::FORBIDDEN)
+        return Err(StatusCode::FORBIDDEN.into_response());
+    }
+
+    if let Some(limiter) = ip_limiter {
+        if limiter.check_key(&remote_addr.ip()).is_err() {
+            tracing::warn!(%remote_addr, method=%req.method(), uri=%req.uri(), "ip_rate_limited");
+            return Err((StatusCode::TOO_MANY_REQUESTS, [(RETRY_AFTER, "1")]).into_response());
+        }
+    }
+
+    Ok(next.run(req).await)
+}
+
+// Drop per-IP state for addresses that have been quiet long enough to be
+// indistinguishable from new ones
+async fn evict_ip_limits(limiter: Arc<DefaultKeyedRateLimiter<IpAddr>>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60));
+    loop {
+        interval.tick().await;
+        limiter.retain_recent();
+        limiter.shrink_to_fit();
+    }
+}
+
+// Like Json, but with an optional Content-Type check and JSON error bodies,
+// along with when reading the request started
+struct Notification(ImseMessage, Instant);
+
+#[axum::async_trait]
+impl FromRequest<Arc<AppState>, Body> for Notification {
+    type Rejection = Response;
+
+    async fn from_request(req: Request<Body>, state: &Arc<AppState>) -> Result<Self, Response> {
+        let started = Instant::now();
+        if state.strict_content_type && !is_json(req.headers()) {
+            return Err(json_error(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "Content-Type must be application/json",
+            ));
+        }
+
+        let body = Bytes::from_request(req, state);
+        let body = match state.read_timeout {
+            Some(limit) => tokio::time::timeout(limit, body).await.map_err(|_| {
+                json_error(
+                    StatusCode::REQUEST_TIMEOUT,
+                    "timed out reading request body",
+                )
+            })?,
+            None => body.await,
+        }
+        .map_err(IntoResponse::into_response)?;
+        serde_json::from_slice(&body)
+            .map(|message| Self(message, started))
+            .map_err(|error| {
+                let status = if error.is_data() {
+                    StatusCode::UNPROCESSABLE_ENTITY
+                } else {
+                    StatusCode::BAD_REQUEST
+                };
+                json_error(status, &error.to_string())
+            })
     }
 }
 
+fn is_json(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(str::trim)
+        .is_some_and(|mime| {
+            mime.eq_ignore_ascii_case("application/json")
+                || (mime.starts_with("application/") && mime.ends_with("+json"))
+        })
+}
+
+fn json_error(status: StatusCode, error: &str) -> Response {
+    (status, Json(serde_json::json!({ "error": error }))).into_response()
+}
+
 #[tracing::instrument(skip_all)]
 async fn notify(
-    State(handlers): State<Arc<Vec<(Handler, HandlerSender)>>>,
+    State(state): State<Arc<AppState>>,
     ConnectInfo(remote_addr): ConnectInfo<SocketAddr>,
-    Json(mut message): Json<ImseMessage>,
+    headers: HeaderMap,
+    Notification(mut message, started): Notification,
 ) -> impl IntoResponse {
-    tracing::info!(%remote_addr, event=?message.event, user=%message.user);
+    // Retried notifications are acknowledged without dispatching again
+    let mut pending_key = None;
+    if let Some(key) = headers
+        .get("x-idempotency-key")
+        .and_then(|key| key.to_str().ok())
+    {
+        let mut keys = state
+            .idempotency_keys
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        if keys.check(key) {
+            tracing::debug!(%remote_addr, %key, user=%message.user, event=%message.event.to_kebab_case(), "idempotent_duplicate");
+            return StatusCode::OK;
+        }
+        pending_key = Some(PendingKey {
+            keys: &state.idempotency_keys,
+            key: Some(key),
+        });
+    }
+
+    // Handler users were lowercased on load
+    if state.user_normalize {
+        message.user = message.user.to_lowercase();
+    }
+
+    // Unselected fields are recorded as None, which omits them entirely
+    let selected = |field| state.event_fields.contains(&field);
+    let remote = selected(EventField::RemoteAddr).then(|| display(remote_addr));
+    let event = selected(EventField::Event).then(|| display(message.event.to_kebab_case()));
+    let user = selected(EventField::User).then(|| display(&message.user));
+    let folder = selected(EventField::Folder).then(|| display(&message.folder));
+    let from = selected(EventField::From)
+        .then(|| message.from.as_deref().map(display))
+        .flatten();
+    let unseen = selected(EventField::Unseen).then_some(message.unseen);
+    if state.filter_events.contains(&message.event) {
+        tracing::trace!(remote_addr = remote, event, user, folder, from, unseen);
+    } else {
+        tracing::info!(remote_addr = remote, event, user, folder, from, unseen);
+    }
     message.remote_addr = Some(remote_addr);
+    message.received_at = Some(SystemTime::now());
+
+    let mut matched = matching_handlers(&state.handlers, &message, remote_addr);
+
+    // Handlers are in configuration order, so later ones are cut off
+    if let Some(position) = matched.iter().position(|(handler, _)| handler.exclusive) {
+        if position + 1 < matched.len() {
+            tracing::debug!(handler=%matched[position].0.label(), skipped=matched.len() - position - 1, "exclusive");
+            matched.truncate(position + 1);
+        }
+    }
+
+    if let Some(max) = state.max_handlers_per_message {
+        if matched.len() > max.get() {
+            tracing::warn!(matched=matched.len(), max, user=%message.user, event=%message.event.to_kebab_case(), "max_handlers_per_message");
+            matched.truncate(max.get());
+        }
+    }
+
+    // The response waits on every matched handler with sync_response, and
+    // otherwise only on those with fail_fast
+    let completions = Arc::new(Completions::default());
+    let waiting: FuturesUnordered<_> = matched
+        .iter()
+        .filter(|(handler, _)| state.sync_response || handler.fail_fast)
+        .map(|(handler, _)| {
+            let completed = completions.wait_for(handler.index);
+            let fail_fast = handler.fail_fast;
+            async move { (fail_fast, completed.await.ok()) }
+        })
+        .collect();
+    let fail_fast = matched
+        .iter()
+        .filter(|(handler, _)| handler.fail_fast)
+        .count();
+    if !waiting.is_empty() {
+        message.completion = Some(completions);
+    }
+
     let message = Arc::new(message);
-    for (_, tx) in handlers
+    for (_, tx) in matched {
+        drop(tx.send(Some(Arc::clone(&message))));
+    }
+
+    // Receivers close if the message is dropped without an outcome
+    drop(message);
+    let status = if waiting.is_empty() {
+        StatusCode::ACCEPTED
+    } else {
+        let completed = wait_completions(waiting, fail_fast, state.sync_response);
+        let outcome = tokio::time::timeout_at(started + state.sync_response_timeout, completed)
+            .await
+            .ok();
+
+        // Handlers are already dispatched, so running out of time to respond
+        // falls back to reporting acceptance
+        match outcome {
+            Some(outcome) if outcome.failed => StatusCode::INTERNAL_SERVER_ERROR,
+            Some(outcome) if outcome.rate_limited => StatusCode::TOO_MANY_REQUESTS,
+            Some(outcome) if outcome.executed => StatusCode::OK,
+            _ => StatusCode::ACCEPTED,
+        }
+    };
+
+    if let (true, Some(pending_key)) = (status.is_success(), pending_key) {
+        pending_key.commit();
+    }
+    status
+}
+
+// An idempotency key recorded for a notification still being handled, which
+// is forgotten unless it succeeds, leaving the sender free to retry
+struct PendingKey<'a> {
+    keys: &'a Mutex<IdempotencyKeys>,
+    key: Option<&'a str>,
+}
+
+impl PendingKey<'_> {
+    fn commit(mut self) {
+        self.key = None;
+    }
+}
+
+impl Drop for PendingKey<'_> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key {
+            self.keys
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .remove(key);
+        }
+    }
+}
+
+// In configuration order, which handlers are dispatched in
+fn matching_handlers<'a>(
+    handlers: &'a [(Handler, HandlerSender)],
+    message: &ImseMessage,
+    remote_addr: SocketAddr,
+) -> Vec<&'a (Handler, HandlerSender)> {
+    handlers
         .iter()
         .filter(|(handler, _)| {
             handler.ip.is_empty() || handler.ip.iter().any(|net| net.contains(&remote_addr.ip()))
         })
-        .filter(|(handler, _)| handler.event == message.event && handler.user == message.user)
-    {
-        drop(tx.send(Some(Arc::clone(&message))));
+        .filter(|(handler, _)| {
+            handler.matches_event(message.event)
+                && handler.matches_user(&message.user)
+                && handler.matches_folder(&message.folder)
+        })
+        .filter(|(handler, _)| match handler.missing_required(message) {
+            Some(field) => {
+                tracing::debug!(handler=%handler.label(), field, "required_field_missing");
+                false
+            }
+            None => true,
+        })
+        .collect()
+}
+
+#[derive(Default)]
+struct Outcome {
+    executed: bool,
+    failed: bool,
+    rate_limited: bool,
+}
+
+// Waits for any fail_fast handler to fail, or all of them to finish, and with
+// sync_response for at least one handler to execute.  Handlers without
+// fail_fast failing or being rate limited doesn't affect the response
+async fn wait_completions<F>(
+    mut waiting: FuturesUnordered<F>,
+    mut fail_fast_left: usize,
+    sync_response: bool,
+) -> Outcome
+where
+    F: Future<Output = (bool, Option<Completion>)>,
+{
+    let mut outcome = Outcome::default();
+    while let Some((fail_fast, completed)) = waiting.next().await {
+        if fail_fast {
+            fail_fast_left -= 1;
+        }
+        match completed {
+            Some(Completion::Success) => outcome.executed = true,
+            Some(Completion::Failed) => {
+                outcome.executed = true;
+                outcome.failed |= fail_fast;
+            }
+            Some(Completion::RateLimited) => outcome.rate_limited |= fail_fast,
+            Some(Completion::Skipped) | None => (),
+        }
+        if outcome.failed || (fail_fast_left == 0 && (outcome.executed || !sync_response)) {
+            break;
+        }
     }
+    outcome
+}
 
-    StatusCode::OK
+// null when everything is allowed
+async fn config_allow(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    Json(
+        state
+            .allow
+            .rules()
+            .map(|rules| rules.iter().map(ToString::to_string).collect::<Vec<_>>()),
+    )
 }
 
 async fn handle_error(error: BoxError) -> impl IntoResponse {
@@ -294,3 +1102,54 @@ async fn shutdown_future() {
         _ = terminate => tracing::info!(kind=%"terminate", "signal"),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dispatch_in_config_order() {
+        let config = Config::from_toml(
+            r#"
+            [[handler]]
+            user = "user"
+            event = "MessageNew"
+            command = "first"
+
+            [[handler]]
+            user = "user"
+            event = "MessageNew"
+            command = "second"
+            "#,
+        )
+        .unwrap();
+        let (handlers, mut receivers): (Vec<_>, Vec<_>) = config
+            .handler
+            .into_iter()
+            .map(|handler| {
+                let (tx, rx) = tokio::sync::watch::channel(None);
+                ((handler, tx), rx)
+            })
+            .unzip();
+        let message: ImseMessage = serde_json::from_value(serde_json::json!({
+            "event": "MessageNew",
+            "user": "user",
+            "unseen": 1,
+            "folder": "INBOX",
+        }))
+        .unwrap();
+
+        let matched = matching_handlers(&handlers, &message, SocketAddr::from(([127, 0, 0, 1], 1)));
+        let order: Vec<_> = matched.iter().map(|(handler, _)| handler.index).collect();
+        assert_eq!(order, [0, 1]);
+
+        let message = Arc::new(message);
+        for (_, tx) in matched {
+            tx.send(Some(Arc::clone(&message))).unwrap();
+        }
+        for rx in &mut receivers {
+            assert!(rx.has_changed().unwrap());
+            assert!(rx.borrow_and_update().is_some());
+        }
+    }
+}
@@ -1,21 +1,92 @@
 use serde::Deserialize;
-use strum::{Display, EnumString};
+use strum::{Display, EnumIter, EnumString, EnumVariantNames, IntoEnumIterator, VariantNames};
+use tokio::sync::oneshot;
+
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+// The outcome of handling a message, for responding synchronously
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Completion {
+    Success,
+    Failed,
+    RateLimited,
+    // Filtered, deduplicated or superseded without executing
+    Skipped,
+}
+
+// A completion sender for each handler the response waits on.  Only the
+// first outcome a handler reports is sent, and any not reported by the time
+// the message is dropped close their receivers
+#[derive(Debug, Default)]
+pub struct Completions(Mutex<HashMap<usize, oneshot::Sender<Completion>>>);
+
+impl Completions {
+    pub fn wait_for(&self, handler: usize) -> oneshot::Receiver<Completion> {
+        let (tx, rx) = oneshot::channel();
+        self.0
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(handler, tx);
+        rx
+    }
+
+    pub fn complete(&self, handler: usize, outcome: Completion) {
+        let sender = self
+            .0
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&handler);
+        if let Some(sender) = sender {
+            drop(sender.send(outcome));
+        }
+    }
+}
 
 #[derive(Deserialize, Clone, Debug)]
 pub struct ImseMessage {
     #[serde(skip)]
     pub remote_addr: Option<std::net::SocketAddr>,
+    #[serde(skip)]
+    pub completion: Option<Arc<Completions>>,
+    #[serde(skip)]
+    pub received_at: Option<std::time::SystemTime>,
     pub event: ImseEvent,
     pub user: String,
     pub unseen: u32,
     pub folder: String,
+    #[serde(rename = "mailboxId", alias = "mailbox_id")]
+    pub mailbox_id: Option<String>,
+    #[serde(
+        default,
+        rename = "imap-uid",
+        alias = "messageUid",
+        alias = "message_uid"
+    )]
+    pub message_uid: Option<u32>,
     pub from: Option<String>,
+    #[serde(alias = "messageSnippet")]
     pub snippet: Option<String>,
 }
 
-#[derive(Copy, Clone, Debug, Display, Deserialize, Hash, PartialEq, Eq, EnumString)]
+#[derive(
+    Copy,
+    Clone,
+    Debug,
+    Display,
+    Deserialize,
+    Hash,
+    PartialEq,
+    Eq,
+    EnumIter,
+    EnumString,
+    EnumVariantNames,
+)]
 #[strum(ascii_case_insensitive)]
-#[serde(try_from = "&str")]
+#[serde(try_from = "String")]
 pub enum ImseEvent {
     FlagsClear,
     FlagsSet,
@@ -31,8 +102,128 @@ pub enum ImseEvent {
     MessageTrash,
 }
 
+impl TryFrom<String> for ImseEvent {
+    type Error = String;
+
+    fn try_from(string: String) -> Result<Self, Self::Error> {
+        Self::from_str(&string)
+            .ok()
+            .or_else(|| Self::from_kebab(&string))
+            .ok_or_else(|| {
+                format!(
+                    "Unknown event '{}'. Valid values: {}",
+                    string,
+                    Self::VARIANTS.join(", ")
+                )
+            })
+    }
+}
+
+impl ImseEvent {
+    // Dovecot's own naming, for log and metric labels
+    pub fn to_kebab_case(self) -> &'static str {
+        match self {
+            Self::FlagsClear => "flags-clear",
+            Self::FlagsSet => "flags-set",
+            Self::MailboxCreate => "mailbox-create",
+            Self::MailboxDelete => "mailbox-delete",
+            Self::MailboxRename => "mailbox-rename",
+            Self::MailboxSubscribe => "mailbox-subscribe",
+            Self::MailboxUnsubscribe => "mailbox-unsubscribe",
+            Self::MessageAppend => "message-append",
+            Self::MessageExpunge => "message-expunge",
+            Self::MessageNew => "message-new",
+            Self::MessageRead => "message-read",
+            Self::MessageTrash => "message-trash",
+        }
+    }
+
+    pub fn from_kebab(s: &str) -> Option<Self> {
+        Self::iter().find(|event| event.to_kebab_case() == s)
+    }
+}
+
+impl ImseMessage {
+    // The fields as received, for passing on to filters and commands
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "event": self.event.to_string(),
+            "user": self.user,
+            "unseen": self.unseen,
+            "folder": self.folder,
+            "mailboxId": self.mailbox_id,
+            "imap-uid": self.message_uid,
+            "from": self.from,
+            "snippet": self.snippet,
+        })
+    }
+}
+
 impl Default for ImseEvent {
     fn default() -> Self {
         Self::MessageNew
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(json: serde_json::Value) -> ImseMessage {
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn field_aliases() {
+        let message = parse(serde_json::json!({
+            "event": "MessageNew",
+            "user": "user",
+            "unseen": 1,
+            "folder": "INBOX",
+            "mailboxId": "abc",
+            "imap-uid": 42,
+            "messageSnippet": "hello",
+        }));
+        assert_eq!(message.mailbox_id.as_deref(), Some("abc"));
+        assert_eq!(message.message_uid, Some(42));
+        assert_eq!(message.snippet.as_deref(), Some("hello"));
+
+        let message = parse(serde_json::json!({
+            "event": "MessageNew",
+            "user": "user",
+            "unseen": 1,
+            "folder": "INBOX",
+            "mailbox_id": "abc",
+            "messageUid": 42,
+        }));
+        assert_eq!(message.mailbox_id.as_deref(), Some("abc"));
+        assert_eq!(message.message_uid, Some(42));
+
+        let message = parse(serde_json::json!({
+            "event": "MessageNew",
+            "user": "user",
+            "unseen": 1,
+            "folder": "INBOX",
+            "message_uid": 42,
+        }));
+        assert_eq!(message.message_uid, Some(42));
+    }
+
+    #[test]
+    fn kebab_case_events() {
+        for event in ImseEvent::iter() {
+            let kebab = event.to_kebab_case().to_string();
+            assert_eq!(ImseEvent::try_from(kebab), Ok(event));
+            assert_eq!(ImseEvent::try_from(event.to_string()), Ok(event));
+        }
+        assert!(ImseEvent::try_from("message-unknown".to_string()).is_err());
+
+        let message = parse(serde_json::json!({
+            "event": "message-new",
+            "user": "user",
+            "unseen": 1,
+            "folder": "INBOX",
+        }));
+        assert_eq!(message.event, ImseEvent::MessageNew);
+    }
+}
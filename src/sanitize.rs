@@ -0,0 +1,331 @@
+use tracing::{
+    field::{display, DisplayValue, Field, Value, Visit},
+    span::Record,
+    Event, Subscriber,
+};
+use tracing_subscriber::{
+    field::{MakeVisitor, RecordFields, VisitFmt, VisitOutput},
+    fmt::{format::Writer, FmtContext, FormatEvent, FormatFields, FormattedFields},
+    registry::LookupSpan,
+};
+
+use std::{collections::HashSet, error::Error, fmt, sync::Arc};
+
+pub const REDACTED: &str = "[REDACTED]";
+
+// Masks the values of named fields in any log format.  Layers can't change
+// what other layers see, so this wraps the event formatter, replaying fields
+// into a copy of the event with the named values replaced
+pub struct SanitizeFormat<F> {
+    inner: F,
+    fields: Arc<HashSet<String>>,
+}
+
+impl<F> SanitizeFormat<F> {
+    pub fn new(inner: F, fields: Arc<HashSet<String>>) -> Self {
+        Self { inner, fields }
+    }
+}
+
+enum Recorded {
+    Str(String),
+    Debug(DisplayValue<String>),
+    I64(i64),
+    U64(u64),
+    Bool(bool),
+    F64(f64),
+}
+
+struct Recorder<'a> {
+    fields: &'a HashSet<String>,
+    values: Vec<(Field, Recorded)>,
+    redacted: bool,
+}
+
+impl Recorder<'_> {
+    fn push(&mut self, field: &Field, value: Recorded) {
+        let value = if self.fields.contains(field.name()) {
+            self.redacted = true;
+            Recorded::Debug(display(REDACTED.to_string()))
+        } else {
+            value
+        };
+        self.values.push((field.clone(), value));
+    }
+}
+
+impl Visit for Recorder<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.push(field, Recorded::Debug(display(format!("{:?}", value))));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.push(field, Recorded::Str(value.to_string()));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.push(field, Recorded::I64(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.push(field, Recorded::U64(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.push(field, Recorded::Bool(value));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.push(field, Recorded::F64(value));
+    }
+}
+
+impl<S, N, F> FormatEvent<S, N> for SanitizeFormat<F>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+    F: FormatEvent<S, N>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        if self.fields.is_empty() {
+            return self.inner.format_event(ctx, writer, event);
+        }
+
+        let mut recorder = Recorder {
+            fields: &self.fields,
+            values: vec![],
+            redacted: false,
+        };
+        event.record(&mut recorder);
+        if !recorder.redacted {
+            return self.inner.format_event(ctx, writer, event);
+        }
+
+        // Strings are borrowed separately, as only &str implements Value
+        let strs: Vec<&str> = recorder
+            .values
+            .iter()
+            .map(|(_, value)| match value {
+                Recorded::Str(value) => value.as_str(),
+                _ => "",
+            })
+            .collect();
+        let values: Vec<(&Field, Option<&dyn Value>)> = recorder
+            .values
+            .iter()
+            .zip(&strs)
+            .map(|((field, value), string)| {
+                let value: &dyn Value = match value {
+                    Recorded::Str(_) => string,
+                    Recorded::Debug(value) => value,
+                    Recorded::I64(value) => value,
+                    Recorded::U64(value) => value,
+                    Recorded::Bool(value) => value,
+                    Recorded::F64(value) => value,
+                };
+                (field, Some(value))
+            })
+            .collect();
+
+        // Events have at most 32 fields, the most a ValueSet takes.  Spare
+        // slots repeat the first field without a value, so aren't recorded
+        let slots: [(&Field, Option<&dyn Value>); 32] =
+            std::array::from_fn(|i| values.get(i).copied().unwrap_or((values[0].0, None)));
+        let metadata = event.metadata();
+        let value_set = metadata.fields().value_set(&slots);
+        let sanitized = if event.is_contextual() {
+            Event::new(metadata, &value_set)
+        } else {
+            Event::new_child_of(event.parent().cloned(), metadata, &value_set)
+        };
+        self.inner.format_event(ctx, writer, &sanitized)
+    }
+}
+
+// Masks the values of named span fields, which are formatted when spans are
+// created or recorded to rather than with each event.  JSON has its own
+// SanitizeJsonFields, as its span fields are merged rather than appended
+pub struct SanitizeFields<M> {
+    inner: M,
+    fields: Arc<HashSet<String>>,
+}
+
+impl<M> SanitizeFields<M> {
+    pub fn new(inner: M, fields: Arc<HashSet<String>>) -> Self {
+        Self { inner, fields }
+    }
+}
+
+impl<T, M: MakeVisitor<T>> MakeVisitor<T> for SanitizeFields<M> {
+    type Visitor = SanitizeVisitor<M::Visitor>;
+
+    fn make_visitor(&self, target: T) -> Self::Visitor {
+        SanitizeVisitor {
+            inner: self.inner.make_visitor(target),
+            fields: Arc::clone(&self.fields),
+        }
+    }
+}
+
+pub struct SanitizeVisitor<V> {
+    inner: V,
+    fields: Arc<HashSet<String>>,
+}
+
+impl<V: Visit> SanitizeVisitor<V> {
+    fn redact(&mut self, field: &Field) -> bool {
+        let redact = self.fields.contains(field.name());
+        if redact {
+            self.inner.record_debug(field, &display(REDACTED));
+        }
+        redact
+    }
+}
+
+impl<V: Visit> Visit for SanitizeVisitor<V> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if !self.redact(field) {
+            self.inner.record_debug(field, value);
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if !self.redact(field) {
+            self.inner.record_str(field, value);
+        }
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        if !self.redact(field) {
+            self.inner.record_i64(field, value);
+        }
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        if !self.redact(field) {
+            self.inner.record_u64(field, value);
+        }
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        if !self.redact(field) {
+            self.inner.record_bool(field, value);
+        }
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        if !self.redact(field) {
+            self.inner.record_f64(field, value);
+        }
+    }
+
+    fn record_error(&mut self, field: &Field, value: &(dyn Error + 'static)) {
+        if !self.redact(field) {
+            self.inner.record_error(field, value);
+        }
+    }
+}
+
+impl<V: VisitOutput<O>, O> VisitOutput<O> for SanitizeVisitor<V> {
+    fn finish(self) -> O {
+        self.inner.finish()
+    }
+}
+
+impl<V: VisitFmt> VisitFmt for SanitizeVisitor<V> {
+    fn writer(&mut self) -> &mut dyn fmt::Write {
+        self.inner.writer()
+    }
+}
+
+// JSON span fields, as a replacement for JsonFields
+pub struct SanitizeJsonFields {
+    fields: Arc<HashSet<String>>,
+}
+
+impl SanitizeJsonFields {
+    pub fn new(fields: Arc<HashSet<String>>) -> Self {
+        Self { fields }
+    }
+}
+
+struct JsonRecorder<'a> {
+    fields: &'a HashSet<String>,
+    values: serde_json::Map<String, serde_json::Value>,
+}
+
+impl JsonRecorder<'_> {
+    fn insert(&mut self, field: &Field, value: serde_json::Value) {
+        let value = if self.fields.contains(field.name()) {
+            REDACTED.into()
+        } else {
+            value
+        };
+        self.values.insert(field.name().to_string(), value);
+    }
+}
+
+impl Visit for JsonRecorder<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.insert(field, format!("{:?}", value).into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.insert(field, value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, value.into());
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.insert(field, value.into());
+    }
+}
+
+impl<'writer> FormatFields<'writer> for SanitizeJsonFields {
+    fn format_fields<R: RecordFields>(
+        &self,
+        mut writer: Writer<'writer>,
+        fields: R,
+    ) -> fmt::Result {
+        let mut recorder = JsonRecorder {
+            fields: &self.fields,
+            values: serde_json::Map::new(),
+        };
+        fields.record(&mut recorder);
+        write!(writer, "{}", serde_json::Value::Object(recorder.values))
+    }
+
+    fn add_fields(
+        &self,
+        current: &'writer mut FormattedFields<Self>,
+        fields: &Record<'_>,
+    ) -> fmt::Result {
+        let values = if current.fields.is_empty() {
+            serde_json::Map::new()
+        } else {
+            serde_json::from_str(&current.fields).map_err(|_| fmt::Error)?
+        };
+        let mut recorder = JsonRecorder {
+            fields: &self.fields,
+            values,
+        };
+        fields.record(&mut recorder);
+        current.fields = serde_json::Value::Object(recorder.values).to_string();
+        Ok(())
+    }
+}
@@ -0,0 +1,58 @@
+use anyhow::Result;
+use serde_json::{Map, Value};
+use tokio::io::AsyncWriteExt;
+
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Debug)]
+pub struct StateStore {
+    path: PathBuf,
+}
+
+impl StateStore {
+    pub fn new<P: AsRef<Path>>(dir: P, name: &str) -> Self {
+        Self {
+            path: dir.as_ref().join(format!("{}.state", name)),
+        }
+    }
+
+    async fn load(&self) -> Result<Map<String, Value>> {
+        match tokio::fs::read(&self.path).await {
+            Ok(data) => Ok(serde_json::from_slice(&data)?),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(Map::new()),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    pub async fn get(&self, key: &str) -> Option<Value> {
+        match self.load().await {
+            Ok(mut state) => state.remove(key),
+            Err(error) => {
+                tracing::warn!(path=%self.path.display(), %error, "state_load");
+                None
+            }
+        }
+    }
+
+    pub async fn set(&self, key: &str, value: Value) -> Result<()> {
+        let mut state = self.load().await.unwrap_or_default();
+        state.insert(key.to_string(), value);
+
+        // Write to a temporary file and rename over the original so a crash
+        // can't leave a partially-written state file behind
+        let tmp = self.path.with_extension("state.tmp");
+        let mut file = tokio::fs::File::create(&tmp).await?;
+        file.write_all(&serde_json::to_vec(&state)?).await?;
+        file.sync_all().await?;
+        drop(file);
+        tokio::fs::rename(&tmp, &self.path).await?;
+
+        // Persist the rename itself, which directories on Windows can't be
+        // opened for
+        #[cfg(unix)]
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            tokio::fs::File::open(dir).await?.sync_all().await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,175 @@
+use anyhow::{anyhow, Context, Result};
+use axum_server::accept::Accept;
+use futures::future::BoxFuture;
+use pkcs8::{EncryptedPrivateKeyInfo, SecretDocument};
+use rustls::{Certificate, PrivateKey, ServerConfig};
+use rustls_pemfile::Item;
+use tokio::net::TcpStream;
+
+use std::{io, sync::Arc};
+
+use crate::config::{HttpVersion, TlsConfig};
+
+pub async fn server_config(
+    tls: &TlsConfig,
+    http_version: HttpVersion,
+) -> Result<Arc<ServerConfig>> {
+    let cert = read_pem(&tls.cert, &tls.cert_pem).await?;
+    let key = read_pem(&tls.key, &tls.key_pem).await?;
+
+    let certs: Vec<_> = rustls_pemfile::certs(&mut &cert[..])?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+
+    if let Some((_, leaf)) = certs
+        .first()
+        .and_then(|cert| x509_parser::parse_x509_certificate(&cert.0).ok())
+    {
+        tracing::info!(subject=%leaf.subject(), not_after=%leaf.validity().not_after, "tls_certificate");
+    }
+
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, private_key(&key, tls.key_password.as_deref())?)?;
+
+    config.alpn_protocols = alpn_protocols(tls, http_version);
+
+    // rustls defaults to stateful resumption only, tickets are opt-in
+    if tls.session_ticket_rotation.is_some() {
+        config.ticketer = rustls::Ticketer::new()?;
+    }
+
+    Ok(Arc::new(config))
+}
+
+async fn read_pem(path: &Option<String>, inline: &Option<String>) -> Result<Vec<u8>> {
+    match (path, inline) {
+        (_, Some(pem)) => Ok(pem.as_bytes().to_vec()),
+        (Some(path), None) => tokio::fs::read(path)
+            .await
+            .with_context(|| format!("reading {}", path)),
+        (None, None) => Err(anyhow!("no PEM file or content configured")),
+    }
+}
+
+fn private_key(pem: &[u8], password: Option<&str>) -> Result<PrivateKey> {
+    if let Some(password) = password {
+        return encrypted_private_key(pem, &expand_env(password)?);
+    }
+
+    for item in rustls_pemfile::read_all(&mut &pem[..])? {
+        match item {
+            Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => {
+                return Ok(PrivateKey(key))
+            }
+            _ => {}
+        }
+    }
+
+    Err(anyhow!("no private key found"))
+}
+
+fn encrypted_private_key(pem: &[u8], password: &str) -> Result<PrivateKey> {
+    let pem = std::str::from_utf8(pem).context("encrypted key is not valid PEM")?;
+    let (label, document) =
+        SecretDocument::from_pem(pem).map_err(|e| anyhow!("reading encrypted key: {}", e))?;
+    if label != "ENCRYPTED PRIVATE KEY" {
+        return Err(anyhow!(
+            "key_password requires an ENCRYPTED PRIVATE KEY, found {}",
+            label
+        ));
+    }
+
+    tracing::info!(key=%"encrypted", "tls");
+    let key = EncryptedPrivateKeyInfo::try_from(document.as_bytes())
+        .map_err(|e| anyhow!("reading encrypted key: {}", e))?
+        .decrypt(password)
+        .map_err(|e| anyhow!("decrypting key, is key_password correct? {}", e))?;
+
+    Ok(PrivateKey(key.as_bytes().to_vec()))
+}
+
+// Replaces ${NAME} with the value of the environment variable NAME
+fn expand_env(value: &str) -> Result<String> {
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| anyhow!("unterminated ${{ in key_password"))?;
+        let name = &rest[start + 2..start + end];
+        expanded.push_str(&rest[..start]);
+        expanded.push_str(
+            &std::env::var(name).with_context(|| format!("key_password variable {}", name))?,
+        );
+        rest = &rest[start + end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+fn alpn_protocols(tls: &TlsConfig, http_version: HttpVersion) -> Vec<Vec<u8>> {
+    if tls.http2_only || http_version == HttpVersion::Http2 {
+        return vec![b"h2".to_vec()];
+    }
+
+    let protocols = match &tls.alpn {
+        Some(alpn) if !alpn.is_empty() => alpn.iter().map(|p| p.as_bytes().to_vec()).collect(),
+        _ => vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+    };
+
+    if http_version == HttpVersion::Http1 {
+        return protocols.into_iter().filter(|p| p != b"h2").collect();
+    }
+
+    protocols
+}
+
+// Logs failed TLS handshakes with the peer address, which the server would
+// otherwise discard silently
+#[derive(Clone, Debug)]
+pub struct HandshakeLogAcceptor<A> {
+    inner: A,
+}
+
+impl<A> HandshakeLogAcceptor<A> {
+    pub fn new(inner: A) -> Self {
+        Self { inner }
+    }
+}
+
+impl<A, S> Accept<TcpStream, S> for HandshakeLogAcceptor<A>
+where
+    A: Accept<TcpStream, S>,
+    A::Future: Send + 'static,
+{
+    type Stream = A::Stream;
+    type Service = A::Service;
+    type Future = BoxFuture<'static, io::Result<(Self::Stream, Self::Service)>>;
+
+    fn accept(&self, stream: TcpStream, service: S) -> Self::Future {
+        let peer = stream.peer_addr().ok();
+        let accept = self.inner.accept(stream, service);
+        Box::pin(async move {
+            accept.await.map_err(|error| {
+                tracing::debug!(?peer, reason = handshake_failure_reason(&error), %error, "tls_handshake");
+                error
+            })
+        })
+    }
+}
+
+fn handshake_failure_reason(error: &io::Error) -> &'static str {
+    match error
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<rustls::Error>())
+    {
+        Some(rustls::Error::PeerIncompatible(_)) => "version_mismatch",
+        Some(rustls::Error::InvalidMessage(_)) => "invalid_message",
+        Some(_) => "tls",
+        None if error.kind() == io::ErrorKind::TimedOut => "timeout",
+        None => "network",
+    }
+}
@@ -0,0 +1,77 @@
+use anyhow::{Context, Result};
+
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+// Users a handler applies to, one per line of a file which is re-read on SIGHUP
+#[derive(Debug)]
+pub struct UserList {
+    path: PathBuf,
+    normalize: bool,
+    users: RwLock<HashSet<String>>,
+}
+
+impl UserList {
+    pub fn load(path: &Path, normalize: bool) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("reading user_list_file {}", path.display()))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            normalize,
+            users: RwLock::new(parse(&contents, normalize)),
+        })
+    }
+
+    pub fn contains(&self, user: &str) -> bool {
+        self.users
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .contains(user)
+    }
+
+    // Failed reads keep the previous list
+    pub async fn refresh(self: Arc<Self>) {
+        #[cfg(unix)]
+        {
+            let mut hangup =
+                match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+                    Ok(hangup) => hangup,
+                    Err(error) => {
+                        tracing::warn!(%error, "user_list_reload");
+                        return;
+                    }
+                };
+
+            while hangup.recv().await.is_some() {
+                match tokio::fs::read_to_string(&self.path).await {
+                    Ok(contents) => {
+                        let users = parse(&contents, self.normalize);
+                        tracing::info!(path=%self.path.display(), count=users.len(), "user_list_reload");
+                        *self.users.write().unwrap_or_else(|e| e.into_inner()) = users;
+                    }
+                    Err(error) => {
+                        tracing::warn!(path=%self.path.display(), %error, "user_list_reload")
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn parse(contents: &str, normalize: bool) -> HashSet<String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|user| {
+            if normalize {
+                user.to_lowercase()
+            } else {
+                user.to_string()
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,91 @@
+use anyhow::{anyhow, Result};
+use futures::FutureExt;
+use wasmtime::{Engine, Instance, Module, Store};
+
+use std::path::{Path, PathBuf};
+
+use crate::message::ImseMessage;
+
+// Filter modules export their linear memory, an alloc(len) -> ptr function
+// for the host to write into, and should_handle(ptr, len) -> i32, returning
+// non-zero to handle the JSON message written at ptr
+pub struct WasmFilter {
+    path: PathBuf,
+    engine: Engine,
+    module: Option<Module>,
+    #[cfg(unix)]
+    hangup: Option<tokio::signal::unix::Signal>,
+}
+
+impl WasmFilter {
+    pub fn new(path: &Path) -> Self {
+        let mut filter = Self {
+            path: path.to_owned(),
+            engine: Engine::default(),
+            module: None,
+            #[cfg(unix)]
+            hangup: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+                .map_err(|error| tracing::warn!(%error, "wasm_filter"))
+                .ok(),
+        };
+        filter.load();
+        filter
+    }
+
+    fn load(&mut self) {
+        match Module::from_file(&self.engine, &self.path) {
+            Ok(module) => {
+                tracing::info!(path=%self.path.display(), "wasm_filter_load");
+                self.module = Some(module);
+            }
+            // Keep any previously loaded module
+            Err(error) => tracing::error!(path=%self.path.display(), %error, "wasm_filter_load"),
+        }
+    }
+
+    // Fails closed, rejecting messages if the module could not be loaded or run
+    pub fn should_handle(&mut self, message: &ImseMessage) -> bool {
+        #[cfg(unix)]
+        if let Some(hangup) = &mut self.hangup {
+            if hangup.recv().now_or_never().flatten().is_some() {
+                self.load();
+            }
+        }
+
+        let module = match &self.module {
+            Some(module) => module,
+            None => return false,
+        };
+
+        match self.call(module, message) {
+            Ok(handle) => handle,
+            Err(error) => {
+                tracing::error!(path=%self.path.display(), %error, "wasm_filter");
+                false
+            }
+        }
+    }
+
+    fn call(&self, module: &Module, message: &ImseMessage) -> Result<bool> {
+        let json = message.to_json().to_string();
+        let len = i32::try_from(json.len())?;
+
+        // A fresh store per call, so modules can't accumulate state
+        let mut store = Store::new(&self.engine, ());
+        let instance = Instance::new(&mut store, module, &[])?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| anyhow!("module does not export memory"))?;
+        let alloc = instance.get_typed_func::<i32, i32>(&mut store, "alloc")?;
+        let should_handle =
+            instance.get_typed_func::<(i32, i32), i32>(&mut store, "should_handle")?;
+
+        let ptr = alloc.call(&mut store, len)?;
+        memory.write(&mut store, usize::try_from(ptr)?, json.as_bytes())?;
+        Ok(should_handle.call(&mut store, (ptr, len))? != 0)
+    }
+}
+
+pub fn check(path: &Path) -> Result<()> {
+    Module::from_file(&Engine::default(), path).map(drop)
+}